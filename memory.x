@@ -1,9 +1,11 @@
 MEMORY {
     BOOT2   : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH   : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    STORAGE : ORIGIN = ORIGIN(FLASH) + LENGTH(FLASH), LENGTH = 2048K
+    /* Everything up to the saved state at 1M, ADDR_OFFSET in main.rs */
+    FLASH   : ORIGIN = 0x10000100, LENGTH = 1024K - 0x100
+    /* The two sectors FlashStorage alternates between */
+    STORAGE : ORIGIN = ORIGIN(FLASH) + LENGTH(FLASH), LENGTH = 8K
     RAM   : ORIGIN = 0x20000000, LENGTH = 264K
 }
 __flash_size = 4194304;
-__storage_flash_size = 2097152;
+__storage_flash_size = 8192;
 __storage_flash_offset = ORIGIN(STORAGE) - ORIGIN(BOOT2);
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pico_fido_core::apdu::Command;
+use pico_fido_fuzz as _;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(command) = Command::parse(data) {
+        // The header is always there, the data has to come from the rest
+        assert!(command.data.len() + 4 <= data.len());
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pico_fido_fuzz as _;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ctap_types::ctap2::Request::deserialize(data);
+});
@@ -0,0 +1,65 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::collections::VecDeque;
+
+use pico_fido_core::ctaphid::{CtapHid, Event, Packet, PACKET_SIZE};
+use pico_fido_fuzz::advance;
+
+// The input is a list of operations on one transport, each starting with
+// an op byte:
+//   0  a packet from the host, the next 64 bytes
+//   1  the authenticator finishing the oldest pending request
+//   2  time passing, the next byte in 50ms steps, then a timeout check
+//   3  a bus reset
+fuzz_target!(|data: &[u8]| {
+    pico_fido_fuzz::reset_clock();
+    let mut ctaphid = CtapHid::new();
+    // Requests handed out and not finished yet, aborted ones included
+    let mut pending = VecDeque::new();
+    let mut data = data;
+
+    while let Some((&op, rest)) = data.split_first() {
+        data = rest;
+        match op % 4 {
+            0 => {
+                if data.len() < PACKET_SIZE {
+                    return;
+                }
+                let (packet, rest) = data.split_at(PACKET_SIZE);
+                data = rest;
+                let packet: Packet = packet.try_into().unwrap();
+
+                match ctaphid.handle_packet(&packet) {
+                    Some(Event::Request(id, message)) => {
+                        // Fragment it again as if it was echoed back
+                        for packet in message.packets() {
+                            assert_eq!(packet.len(), PACKET_SIZE);
+                        }
+                        pending.push_back(id);
+                    }
+                    Some(Event::Response(response) | Event::Abort(_, response)) => {
+                        let _ = response.to_message().packets().count();
+                    }
+                    Some(Event::Cancel(_)) | None => {}
+                }
+            }
+            1 => {
+                if let Some(id) = pending.pop_front() {
+                    ctaphid.finish(id);
+                }
+            }
+            2 => {
+                let Some((&steps, rest)) = data.split_first() else {
+                    return;
+                };
+                data = rest;
+                advance(steps);
+                if let Some(Event::Response(response)) = ctaphid.timeout() {
+                    let _ = response.to_message().packets().count();
+                }
+            }
+            _ => ctaphid.reset(),
+        }
+    }
+});
@@ -0,0 +1,59 @@
+#![no_main]
+
+use embassy_futures::block_on;
+use libfuzzer_sys::fuzz_target;
+use pico_fido_core::dispatch::{Buffer, Request};
+use pico_fido_fuzz::advance;
+
+// The input is a list of operations run against one authenticator, so
+// requests can build on each other. Each starts with an op byte:
+//   0x00..=0x3f  a ctap2 request, the op byte is the command
+//   0x40..=0x7f  a vendor command, the op byte is the command
+//   0x80..=0xbf  a ctap1 APDU
+// each followed by a two byte little endian length and the request, or
+//   0xc0..=0xdf  time passing, the next byte in 50ms steps
+//   0xe0..=0xff  a power cycle, starting over from a reset authenticator
+fuzz_target!(|data: &[u8]| {
+    let mut dispatcher = pico_fido_fuzz::dispatcher();
+    let mut response = Buffer::new();
+    let mut data = data;
+
+    while let Some((&op, rest)) = data.split_first() {
+        data = rest;
+        match op {
+            0xc0..=0xdf => {
+                let Some((&steps, rest)) = data.split_first() else {
+                    return;
+                };
+                data = rest;
+                advance(steps);
+                continue;
+            }
+            0xe0..=0xff => {
+                dispatcher = pico_fido_fuzz::dispatcher();
+                continue;
+            }
+            _ => {}
+        }
+
+        let [low, high, rest @ ..] = data else {
+            return;
+        };
+        let len = usize::from(u16::from_le_bytes([*low, *high])).min(rest.len());
+        let (body, rest) = rest.split_at(len);
+        data = rest;
+
+        let mut cbor = Buffer::new();
+        let request = match op {
+            // The command byte leads the cbor parameters
+            0x00..=0x3f => {
+                let _ = cbor.push(op);
+                let _ = cbor.extend_from_slice(body);
+                Request::Cbor(&cbor)
+            }
+            0x40..=0x7f => Request::Vendor(op, body),
+            _ => Request::Apdu(body),
+        };
+        let _ = block_on(dispatcher.dispatch(request, &mut response));
+    }
+});
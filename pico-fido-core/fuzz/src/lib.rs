@@ -0,0 +1,62 @@
+//! Shared setup for the fuzz targets, which run the authenticator on the
+//! host platform from `pico_fido_core::host`. Run a target with
+//! `cargo fuzz run <target>` from `pico-fido-core`.
+
+use embassy_futures::block_on;
+use embassy_time::{Duration, MockDriver};
+use pico_fido_core::ctap::Ctap;
+use pico_fido_core::dispatch::{Buffer, Dispatcher, Error, VendorCommand};
+use pico_fido_core::host::{AlwaysPresent, Host, MemoryStorage, XorShiftRng};
+use pico_fido_core::platform::SystemClock;
+
+pub type Fuzz = Host<XorShiftRng>;
+
+// Vendor commands for the dispatcher to route, one that answers and one
+// that fails
+pub const ECHO: u8 = 0x41;
+pub const REJECT: u8 = 0x42;
+
+static VENDOR_COMMANDS: &[VendorCommand] = &[
+    VendorCommand {
+        cmd: ECHO,
+        handler: echo,
+    },
+    VendorCommand {
+        cmd: REJECT,
+        handler: reject,
+    },
+];
+
+fn echo(request: &[u8], response: &mut Buffer) -> Result<(), Error> {
+    response
+        .extend_from_slice(request)
+        .map_err(|_| Error::InvalidLength)
+}
+
+fn reject(_request: &[u8], _response: &mut Buffer) -> Result<(), Error> {
+    Err(Error::InvalidParameter)
+}
+
+/// A freshly reset authenticator at boot, with the vendor commands above
+pub fn dispatcher() -> Dispatcher<Fuzz> {
+    reset_clock();
+    let ctap = Ctap::<Fuzz>::new(
+        MemoryStorage::default(),
+        XorShiftRng::default(),
+        AlwaysPresent,
+        SystemClock,
+    );
+    Dispatcher::new(block_on(ctap), VENDOR_COMMANDS)
+}
+
+/// Goes back to boot time. Every input starts there, the clock is shared
+/// by the whole process
+pub fn reset_clock() {
+    MockDriver::get().reset();
+}
+
+/// Moves the clock on by `steps` times 50ms, long enough in total to run
+/// out every timeout the authenticator and the transport have
+pub fn advance(steps: u8) {
+    MockDriver::get().advance(Duration::from_millis(50) * u32::from(steps));
+}
@@ -0,0 +1,93 @@
+// ISO 7816-4 command APDUs, which is how CTAP1 (U2F) requests are framed
+// over every transport:
+//   CLA (1) | INS (1) | P1 (1) | P2 (1) | [Lc | DATA] | [Le]
+// Lc and Le are one byte each in the short encoding. In the extended
+// encoding Lc is a zero byte followed by two bytes and Le is two bytes.
+
+/// Status word sent at the end of every response APDU
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum Status {
+    NoError = 0x9000,
+    WrongLength = 0x6700,
+    ConditionsNotSatisfied = 0x6985,
+    WrongData = 0x6a80,
+    InsNotSupported = 0x6d00,
+    ClaNotSupported = 0x6e00,
+}
+
+impl Status {
+    pub fn to_be_bytes(self) -> [u8; 2] {
+        (self as u16).to_be_bytes()
+    }
+}
+
+pub struct Command<'a> {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: &'a [u8],
+    // Longest response the host accepts, 0 if it doesn't expect any data
+    pub le: usize,
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(apdu: &'a [u8]) -> Result<Self, Status> {
+        let (&[cla, ins, p1, p2], body) = apdu.split_first_chunk().ok_or(Status::WrongLength)?;
+        let (data, le) = match body {
+            [] => (&[][..], 0),
+            // Short Le only, zero means 256
+            [le] => (&[][..], short_le(*le)),
+            // Extended Le only, zero means 65536
+            [0, high, low] => (&[][..], extended_le(*high, *low)),
+            [0, high, low, rest @ ..] => {
+                let lc = u16::from_be_bytes([*high, *low]) as usize;
+                if lc == 0 || rest.len() < lc {
+                    return Err(Status::WrongLength);
+                }
+                let (data, le) = rest.split_at(lc);
+                match le {
+                    [] => (data, 0),
+                    [high, low] => (data, extended_le(*high, *low)),
+                    _ => return Err(Status::WrongLength),
+                }
+            }
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                if rest.len() < lc {
+                    return Err(Status::WrongLength);
+                }
+                let (data, le) = rest.split_at(lc);
+                match le {
+                    [] => (data, 0),
+                    [le] => (data, short_le(*le)),
+                    _ => return Err(Status::WrongLength),
+                }
+            }
+        };
+
+        Ok(Command {
+            cla,
+            ins,
+            p1,
+            p2,
+            data,
+            le,
+        })
+    }
+}
+
+fn short_le(le: u8) -> usize {
+    match le {
+        0 => 256,
+        le => le as usize,
+    }
+}
+
+fn extended_le(high: u8, low: u8) -> usize {
+    match u16::from_be_bytes([high, low]) {
+        0 => 65536,
+        le => le as usize,
+    }
+}
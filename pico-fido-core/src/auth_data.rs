@@ -0,0 +1,54 @@
+// Authenticator data, the bytes a credential signs over:
+//   rpIdHash (32) | flags (1) | signCount (4, big endian) | attestedCredentialData
+// where the attested credential data, only there when a credential is made, is
+//   aaguid (16) | credentialIdLength (2, big endian) | credentialId | COSE_Key
+
+use ctap_types::ctap2::Error;
+use ctap_types::Vec;
+
+use crate::credential::{SecretKey, COSE_KEY_LEN};
+
+pub const FLAG_USER_PRESENT: u8 = 0x01;
+pub const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// Credential ids are at most this long
+pub const CREDENTIAL_ID_LEN: usize = 128;
+
+pub const AUTH_DATA_LEN: usize = 32 + 1 + 4 + 16 + 2 + CREDENTIAL_ID_LEN + COSE_KEY_LEN;
+
+pub struct AuthData(Vec<u8, AUTH_DATA_LEN>);
+
+impl AuthData {
+    pub fn new(rp_id_hash: &[u8; 32], flags: u8, sign_count: u32) -> Self {
+        let mut data = Vec::new();
+        // The fixed part always fits
+        data.extend_from_slice(rp_id_hash).unwrap();
+        data.push(flags).unwrap();
+        data.extend_from_slice(&sign_count.to_be_bytes()).unwrap();
+        AuthData(data)
+    }
+
+    /// Appends the attested credential data. The caller sets the AT flag
+    pub fn attest(
+        &mut self,
+        aaguid: &[u8; 16],
+        credential_id: &[u8],
+        key: &SecretKey,
+    ) -> Result<(), Error> {
+        let id_len = u16::try_from(credential_id.len()).map_err(|_| Error::Other)?;
+        let public_key = key.public_key()?;
+        for part in [
+            &aaguid[..],
+            &id_len.to_be_bytes()[..],
+            credential_id,
+            &public_key[..],
+        ] {
+            self.0.extend_from_slice(part).map_err(|_| Error::Other)?;
+        }
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
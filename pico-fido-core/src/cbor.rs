@@ -0,0 +1,231 @@
+// Just enough cbor to write COSE keys and to read the parts of requests
+// ctap-types doesn't hand over. Every item has a header byte with the major
+// type in the top 3 bits and either the argument or its size in the rest:
+//   0-23 the argument itself, 24 to 27 a 1, 2, 4 or 8 byte argument follows
+// Ctap requires definite lengths, so indefinite ones are rejected.
+
+use ctap_types::ctap2::Error;
+use ctap_types::Vec;
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+// Requests never nest anywhere near this deep
+const MAX_DEPTH: usize = 8;
+
+pub struct Writer<'a, const N: usize> {
+    buf: &'a mut Vec<u8, N>,
+}
+
+impl<'a, const N: usize> Writer<'a, N> {
+    pub fn new(buf: &'a mut Vec<u8, N>) -> Self {
+        Writer { buf }
+    }
+
+    fn header(&mut self, major: u8, arg: u64) -> Result<(), Error> {
+        let major = major << 5;
+        let (info, len) = match arg {
+            0..=23 => (arg as u8, 0),
+            24..=0xff => (24, 1),
+            0x100..=0xffff => (25, 2),
+            0x1_0000..=0xffff_ffff => (26, 4),
+            _ => (27, 8),
+        };
+        self.buf.push(major | info).map_err(|_| Error::Other)?;
+        self.buf
+            .extend_from_slice(&arg.to_be_bytes()[8 - len..])
+            .map_err(|_| Error::Other)
+    }
+
+    pub fn int(&mut self, value: i64) -> Result<(), Error> {
+        match value {
+            0.. => self.header(UNSIGNED, value as u64),
+            _ => self.header(NEGATIVE, !value as u64),
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<(), Error> {
+        self.header(BYTES, value.len() as u64)?;
+        self.buf.extend_from_slice(value).map_err(|_| Error::Other)
+    }
+
+    pub fn map(&mut self, len: usize) -> Result<(), Error> {
+        self.header(MAP, len as u64)
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.data.len() {
+            return Err(Error::InvalidCbor);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn header(&mut self) -> Result<(u8, u64), Error> {
+        let header = self.take(1)?[0];
+        let len = match header & 0x1f {
+            info @ 0..=23 => return Ok((header >> 5, info as u64)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(Error::InvalidCbor),
+        };
+        let mut arg = [0; 8];
+        arg[8 - len..].copy_from_slice(self.take(len)?);
+        Ok((header >> 5, u64::from_be_bytes(arg)))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64, Error> {
+        match self.header()? {
+            (found, arg) if found == major => Ok(arg),
+            _ => Err(Error::CborUnexpectedType),
+        }
+    }
+
+    pub fn int(&mut self) -> Result<i64, Error> {
+        let (major, arg) = self.header()?;
+        let value = i64::try_from(arg).map_err(|_| Error::InvalidCbor)?;
+        match major {
+            UNSIGNED => Ok(value),
+            NEGATIVE => Ok(-1 - value),
+            _ => Err(Error::CborUnexpectedType),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.expect(BYTES)?;
+        self.take(usize::try_from(len).map_err(|_| Error::InvalidCbor)?)
+    }
+
+    pub fn text(&mut self) -> Result<&'a str, Error> {
+        let len = self.expect(TEXT)?;
+        let text = self.take(usize::try_from(len).map_err(|_| Error::InvalidCbor)?)?;
+        core::str::from_utf8(text).map_err(|_| Error::InvalidCbor)
+    }
+
+    /// Number of entries in the map that follows
+    pub fn map(&mut self) -> Result<u64, Error> {
+        self.expect(MAP)
+    }
+
+    /// Number of items in the array that follows
+    pub fn array(&mut self) -> Result<u64, Error> {
+        self.expect(ARRAY)
+    }
+
+    /// Steps over the next item, whatever it is
+    pub fn skip(&mut self) -> Result<(), Error> {
+        self.skip_nested(0)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InvalidCbor);
+        }
+        let (major, arg) = self.header()?;
+        let items = match major {
+            UNSIGNED | NEGATIVE | SIMPLE => 0,
+            BYTES | TEXT => {
+                self.take(usize::try_from(arg).map_err(|_| Error::InvalidCbor)?)?;
+                0
+            }
+            ARRAY => arg,
+            MAP => arg.checked_mul(2).ok_or(Error::InvalidCbor)?,
+            TAG => 1,
+            _ => return Err(Error::InvalidCbor),
+        };
+        for _ in 0..items {
+            self.skip_nested(depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Skips one item from `data` and returns what is left after it
+    fn skip(data: &[u8]) -> Result<&[u8], Error> {
+        let mut reader = Reader::new(data);
+        reader.skip()?;
+        Ok(reader.data)
+    }
+
+    #[test]
+    fn skip_steps_over_one_item() {
+        let items: &[&[u8]] = &[
+            // 0, 24, 65536 and -300
+            &[0x00],
+            &[0x18, 0x18],
+            &[0x1a, 0x00, 0x01, 0x00, 0x00],
+            &[0x39, 0x01, 0x2b],
+            // h'0102', "alg"
+            &[0x42, 0x01, 0x02],
+            &[0x63, b'a', b'l', b'g'],
+            // [1, [2]], {"a": {1: 2}}
+            &[0x82, 0x01, 0x81, 0x02],
+            &[0xa1, 0x61, b'a', 0xa1, 0x01, 0x02],
+            // A tagged item, true
+            &[0xc1, 0x1a, 0x00, 0x00, 0x00, 0x00],
+            &[0xf5],
+        ];
+        for item in items {
+            let mut data: Vec<u8, 16> = Vec::from_slice(item).unwrap();
+            data.push(0xff).unwrap();
+            assert_eq!(skip(&data).ok(), Some(&[0xff][..]), "{:02x?}", item);
+        }
+    }
+
+    #[test]
+    fn skip_rejects_malformed_items() {
+        let items: &[&[u8]] = &[
+            &[],
+            // Arguments and contents that run past the end
+            &[0x19, 0x01],
+            &[0x43, 0x01, 0x02],
+            &[0x82, 0x01],
+            &[0xa1, 0x01],
+            // Indefinite lengths
+            &[0x5f, 0x41, 0x00, 0xff],
+            &[0x9f, 0xff],
+            // A reserved argument size
+            &[0x1c],
+            // A length no map can have
+            &[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ];
+        for item in items {
+            assert!(
+                matches!(skip(item), Err(Error::InvalidCbor)),
+                "{:02x?}",
+                item
+            );
+        }
+    }
+
+    #[test]
+    fn skip_limits_nesting() {
+        let mut nested = [0x81; MAX_DEPTH + 2];
+        nested[MAX_DEPTH + 1] = 0x00;
+        assert!(matches!(skip(&nested), Err(Error::InvalidCbor)));
+        assert!(skip(&nested[1..]).is_ok());
+    }
+}
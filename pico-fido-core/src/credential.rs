@@ -0,0 +1,342 @@
+// Credential key pairs for each algorithm makeCredential can create, and the
+// parts of makeCredential ctap-types doesn't parse for us.
+
+use ctap_types::ctap2::Error;
+use ctap_types::Vec;
+use p256::ecdsa::signature::Signer;
+use rand_core::{CryptoRngCore, RngCore};
+
+use crate::cbor::{Reader, Writer};
+
+// pubKeyCredParams in the makeCredential parameter map
+const PUB_KEY_CRED_PARAMS: i64 = 0x04;
+
+// COSE key parameters, RFC 9053
+const COSE_KTY: i64 = 1;
+const COSE_ALG: i64 = 3;
+const COSE_CRV: i64 = -1;
+const COSE_X: i64 = -2;
+const COSE_Y: i64 = -3;
+const COSE_KTY_OKP: i64 = 1;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+const COSE_CRV_ED25519: i64 = 6;
+const COSE_CRV_SECP256K1: i64 = 8;
+
+// Fits the largest COSE public key written here
+pub const COSE_KEY_LEN: usize = 80;
+
+// Longest signature any algorithm makes, a DER encoded ECDSA one.
+// Ed25519 signatures are always 64 bytes
+pub const SIGNATURE_LEN: usize = 72;
+
+pub type CoseKey = Vec<u8, COSE_KEY_LEN>;
+pub type Signature = Vec<u8, SIGNATURE_LEN>;
+
+/// Every algorithm credentials can be made with, in the order getInfo
+/// reports them. makeCredential takes the relying party's order instead,
+/// see `negotiate_algorithm`
+pub const ALGORITHMS: &[Algorithm] = &[Algorithm::Es256, Algorithm::EdDsa, Algorithm::Es256k];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    // ECDSA over P-256 with SHA-256
+    Es256,
+    // ECDSA over secp256k1 with SHA-256
+    Es256k,
+    // Ed25519
+    EdDsa,
+}
+
+impl Algorithm {
+    /// The COSE algorithm identifier
+    pub const fn id(self) -> i32 {
+        match self {
+            Algorithm::Es256 => -7,
+            Algorithm::Es256k => -47,
+            Algorithm::EdDsa => -8,
+        }
+    }
+
+    /// The supported algorithm with this COSE identifier
+    pub fn from_id(id: i64) -> Option<Self> {
+        ALGORITHMS
+            .iter()
+            .copied()
+            .find(|alg| i64::from(alg.id()) == id)
+    }
+}
+
+pub enum SecretKey {
+    Es256(p256::ecdsa::SigningKey),
+    Es256k(k256::ecdsa::SigningKey),
+    EdDsa(ed25519_dalek::SigningKey),
+}
+
+impl SecretKey {
+    pub fn generate(alg: Algorithm, rng: &mut impl CryptoRngCore) -> Self {
+        match alg {
+            Algorithm::Es256 => SecretKey::Es256(p256::ecdsa::SigningKey::random(rng)),
+            Algorithm::Es256k => SecretKey::Es256k(k256::ecdsa::SigningKey::random(rng)),
+            Algorithm::EdDsa => {
+                let mut seed = [0; 32];
+                rng.fill_bytes(&mut seed);
+                SecretKey::EdDsa(ed25519_dalek::SigningKey::from_bytes(&seed))
+            }
+        }
+    }
+
+    /// Restores a key from what `to_bytes` returned
+    pub fn from_bytes(alg: Algorithm, bytes: &[u8]) -> Option<Self> {
+        match alg {
+            Algorithm::Es256 => p256::ecdsa::SigningKey::from_slice(bytes)
+                .ok()
+                .map(SecretKey::Es256),
+            Algorithm::Es256k => k256::ecdsa::SigningKey::from_slice(bytes)
+                .ok()
+                .map(SecretKey::Es256k),
+            Algorithm::EdDsa => bytes
+                .try_into()
+                .ok()
+                .map(|seed| SecretKey::EdDsa(ed25519_dalek::SigningKey::from_bytes(seed))),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        match self {
+            SecretKey::Es256(key) => key.to_bytes().into(),
+            SecretKey::Es256k(key) => key.to_bytes().into(),
+            SecretKey::EdDsa(key) => key.to_bytes(),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            SecretKey::Es256(_) => Algorithm::Es256,
+            SecretKey::Es256k(_) => Algorithm::Es256k,
+            SecretKey::EdDsa(_) => Algorithm::EdDsa,
+        }
+    }
+
+    /// The public key as the COSE_Key that goes in the attested credential data
+    pub fn public_key(&self) -> Result<CoseKey, Error> {
+        let mut key = CoseKey::new();
+        let mut cose = Writer::new(&mut key);
+        match self {
+            SecretKey::Es256(secret) => {
+                let point = secret.verifying_key().to_encoded_point(false);
+                ec2_key(&mut cose, Algorithm::Es256, COSE_CRV_P256, point.as_bytes())?;
+            }
+            SecretKey::Es256k(secret) => {
+                let point = secret.verifying_key().to_encoded_point(false);
+                ec2_key(
+                    &mut cose,
+                    Algorithm::Es256k,
+                    COSE_CRV_SECP256K1,
+                    point.as_bytes(),
+                )?;
+            }
+            SecretKey::EdDsa(secret) => {
+                cose.map(4)?;
+                cose.int(COSE_KTY)?;
+                cose.int(COSE_KTY_OKP)?;
+                cose.int(COSE_ALG)?;
+                cose.int(Algorithm::EdDsa.id().into())?;
+                cose.int(COSE_CRV)?;
+                cose.int(COSE_CRV_ED25519)?;
+                cose.int(COSE_X)?;
+                cose.bytes(secret.verifying_key().as_bytes())?;
+            }
+        }
+        Ok(key)
+    }
+
+    /// Signs `message` the way webauthn expects for the key's algorithm
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        match self {
+            SecretKey::Es256(key) => {
+                let signature: p256::ecdsa::Signature = key.sign(message);
+                let der = signature.to_der();
+                // A DER encoded signature over a 256 bit curve is at most 72 bytes
+                Vec::from_slice(der.as_bytes()).unwrap()
+            }
+            SecretKey::Es256k(key) => {
+                let signature: k256::ecdsa::Signature = key.sign(message);
+                let der = signature.to_der();
+                Vec::from_slice(der.as_bytes()).unwrap()
+            }
+            // The raw 64 byte signature, webauthn doesn't wrap it
+            SecretKey::EdDsa(key) => Vec::from_slice(&key.sign(message).to_bytes()).unwrap(),
+        }
+    }
+}
+
+// Writes an EC2 key from an uncompressed SEC1 point, 0x04 | x | y
+fn ec2_key<const N: usize>(
+    cose: &mut Writer<'_, N>,
+    alg: Algorithm,
+    curve: i64,
+    point: &[u8],
+) -> Result<(), Error> {
+    let [0x04, coordinates @ ..] = point else {
+        return Err(Error::Other);
+    };
+    let (x, y) = coordinates.split_at(coordinates.len() / 2);
+    cose.map(5)?;
+    cose.int(COSE_KTY)?;
+    cose.int(COSE_KTY_EC2)?;
+    cose.int(COSE_ALG)?;
+    cose.int(alg.id().into())?;
+    cose.int(COSE_CRV)?;
+    cose.int(curve)?;
+    cose.int(COSE_X)?;
+    cose.bytes(x)?;
+    cose.int(COSE_Y)?;
+    cose.bytes(y)
+}
+
+/// Picks the algorithm for a new credential from pubKeyCredParams, the first
+/// entry the relying party lists that is supported. ctap-types drops the
+/// entries it doesn't know while parsing the request, so they are read from
+/// the request as sent. `request` is the makeCredential parameter map,
+/// without the command byte.
+///
+/// Every entry has to have an integer alg and a text type, even the ones
+/// that aren't used, and the request is rejected if one doesn't
+pub fn negotiate_algorithm(request: &[u8]) -> Result<Algorithm, Error> {
+    let mut request = Reader::new(request);
+    let mut chosen = None;
+    let mut found = false;
+    for _ in 0..request.map()? {
+        if request.int()? != PUB_KEY_CRED_PARAMS {
+            request.skip()?;
+            continue;
+        }
+        found = true;
+        for _ in 0..request.array()? {
+            let mut alg = None;
+            let mut key_type = None;
+            for _ in 0..request.map()? {
+                match request.text()? {
+                    "alg" => alg = Some(request.int()?),
+                    "type" => key_type = Some(request.text()?),
+                    _ => request.skip()?,
+                }
+            }
+            let (Some(alg), Some(key_type)) = (alg, key_type) else {
+                return Err(Error::MissingParameter);
+            };
+            // Only public key credentials are made, other types are skipped
+            if chosen.is_none() && key_type == "public-key" {
+                chosen = Algorithm::from_id(alg);
+            }
+        }
+    }
+    if !found {
+        return Err(Error::MissingParameter);
+    }
+    chosen.ok_or(Error::UnsupportedAlgorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // COSE identifiers of algorithms that aren't supported, RS256 and PS256
+    const RS256: i64 = -257;
+    const PS256: i64 = -37;
+
+    // A makeCredential parameter map with a clientDataHash ahead of
+    // pubKeyCredParams, made of entries with an optional alg and type
+    fn request(entries: &[(Option<i64>, Option<&str>)]) -> Vec<u8, 256> {
+        fn text(buf: &mut Vec<u8, 256>, text: &str) {
+            buf.push(0x60 | text.len() as u8).unwrap();
+            buf.extend_from_slice(text.as_bytes()).unwrap();
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&[0xa2, 0x01, 0x42, 0xaa, 0xbb, 0x04])
+            .unwrap();
+        buf.push(0x80 | entries.len() as u8).unwrap();
+        for (alg, key_type) in entries {
+            let len = alg.is_some() as u8 + key_type.is_some() as u8;
+            buf.push(0xa0 | len).unwrap();
+            if let Some(alg) = alg {
+                text(&mut buf, "alg");
+                Writer::new(&mut buf).int(*alg).unwrap();
+            }
+            if let Some(key_type) = key_type {
+                text(&mut buf, "type");
+                text(&mut buf, key_type);
+            }
+        }
+        buf
+    }
+
+    fn public_key(alg: Algorithm) -> (Option<i64>, Option<&'static str>) {
+        (Some(alg.id().into()), Some("public-key"))
+    }
+
+    #[test]
+    fn relying_party_order_wins() {
+        // The reverse of ALGORITHMS
+        let params = request(&[
+            public_key(Algorithm::Es256k),
+            public_key(Algorithm::EdDsa),
+            public_key(Algorithm::Es256),
+        ]);
+        assert!(matches!(
+            negotiate_algorithm(&params),
+            Ok(Algorithm::Es256k)
+        ));
+    }
+
+    #[test]
+    fn unsupported_algorithms_are_skipped() {
+        let params = request(&[
+            (Some(RS256), Some("public-key")),
+            public_key(Algorithm::EdDsa),
+        ]);
+        assert!(matches!(negotiate_algorithm(&params), Ok(Algorithm::EdDsa)));
+
+        let params = request(&[
+            (Some(RS256), Some("public-key")),
+            (Some(PS256), Some("public-key")),
+        ]);
+        assert!(matches!(
+            negotiate_algorithm(&params),
+            Err(Error::UnsupportedAlgorithm)
+        ));
+    }
+
+    #[test]
+    fn other_credential_types_are_skipped() {
+        let params = request(&[
+            (Some(Algorithm::Es256.id().into()), Some("other")),
+            public_key(Algorithm::EdDsa),
+        ]);
+        assert!(matches!(negotiate_algorithm(&params), Ok(Algorithm::EdDsa)));
+    }
+
+    #[test]
+    fn entries_need_alg_and_type() {
+        // Even after a supported entry
+        for broken in [(None, Some("public-key")), (Some(RS256), None)] {
+            let params = request(&[public_key(Algorithm::Es256), broken]);
+            assert!(matches!(
+                negotiate_algorithm(&params),
+                Err(Error::MissingParameter)
+            ));
+        }
+    }
+
+    #[test]
+    fn pub_key_cred_params_is_required() {
+        // Only a clientDataHash
+        let params = [0xa1, 0x01, 0x42, 0xaa, 0xbb];
+        assert!(matches!(
+            negotiate_algorithm(&params),
+            Err(Error::MissingParameter)
+        ));
+    }
+}
@@ -0,0 +1,906 @@
+use ctap_types::authenticator::Ctap1Authenticator;
+use ctap_types::ctap1::*;
+use ctap_types::ctap2::*;
+use ctap_types::sizes::MAX_CREDENTIAL_COUNT_IN_LIST;
+use ctap_types::webauthn::{
+    PublicKeyCredentialDescriptor, PublicKeyCredentialParameters, PublicKeyCredentialUserEntity,
+};
+use ctap_types::{Bytes, Vec};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::info;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::auth_data::{AuthData, AUTH_DATA_LEN, FLAG_ATTESTED_CREDENTIAL, FLAG_USER_PRESENT};
+use crate::credential::{negotiate_algorithm, Algorithm, SecretKey, Signature, ALGORITHMS};
+use crate::ctaphid::DEVICE_VERSION;
+use crate::dispatch::MAX_MSG_SIZE;
+use crate::keys::{CtapCredential, Keys, MAX_CREDENTIALS, MAX_ID_LEN, STORED_ID_LEN};
+use crate::platform::{Clock, Platform, StorageError, UserPresence};
+
+// Identifies this authenticator model to relying parties. Builds for a
+// different model set PICO_FIDO_AAGUID to their own uuid
+pub const AAGUID: [u8; 16] = parse_aaguid(match option_env!("PICO_FIDO_AAGUID") {
+    Some(aaguid) => aaguid,
+    None => "91aad63d-3ec9-4d09-8d2d-629c4e9b6992",
+});
+
+// Reads a uuid like 01234567-89ab-cdef-0123-456789abcdef, dashes are optional
+const fn parse_aaguid(uuid: &str) -> [u8; 16] {
+    let digits = uuid.as_bytes();
+    let mut aaguid = [0; 16];
+    let mut nibbles = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        let nibble = match digits[i] {
+            b'0'..=b'9' => digits[i] - b'0',
+            b'a'..=b'f' => digits[i] - b'a' + 10,
+            b'A'..=b'F' => digits[i] - b'A' + 10,
+            b'-' => {
+                i += 1;
+                continue;
+            }
+            _ => panic!("PICO_FIDO_AAGUID has to be a uuid"),
+        };
+        if nibbles == 32 {
+            panic!("PICO_FIDO_AAGUID is longer than 16 bytes");
+        }
+        aaguid[nibbles / 2] |= nibble << (4 * (1 - nibbles % 2));
+        nibbles += 1;
+        i += 1;
+    }
+    if nibbles != 32 {
+        panic!("PICO_FIDO_AAGUID is shorter than 16 bytes");
+    }
+    aaguid
+}
+
+// Same major, minor and patch numbers ctaphid reports, packed into one
+const FIRMWARE_VERSION: usize = (DEVICE_VERSION[0] as usize) << 16
+    | (DEVICE_VERSION[1] as usize) << 8
+    | DEVICE_VERSION[2] as usize;
+
+// How long the user has to confirm before a request fails
+const USER_PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Reset is only allowed this soon after power up
+const RESET_WINDOW: Duration = Duration::from_secs(10);
+
+// Signaled by the transport when the host cancels the pending request
+pub static CANCEL_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Set while the authenticator is waiting on the user, so transports
+// can tell the host (CTAPHID keepalives report UPNEEDED)
+pub static USER_PRESENCE_PENDING: AtomicBool = AtomicBool::new(false);
+
+pub struct Ctap<P: Platform> {
+    storage: P::Storage,
+    rng: P::Rng,
+    presence: P::UserPresence,
+    clock: P::Clock,
+    keys: Keys,
+    // Set when the saved keys can't be read. They are kept until the user
+    // resets the authenticator, so nothing that would use or replace them works
+    unreadable: bool,
+}
+
+impl<P: Platform> Ctap<P> {
+    /// Sets up the authenticator with the state saved in `storage`. If that
+    /// state can't be read only getInfo, selection and reset work, so the
+    /// state isn't lost unless the user chooses to start over
+    pub async fn new(
+        mut storage: P::Storage,
+        mut rng: P::Rng,
+        presence: P::UserPresence,
+        clock: P::Clock,
+    ) -> Self {
+        let (keys, unreadable) = match Keys::load(&mut storage, &mut rng).await {
+            Ok(keys) => (keys, false),
+            Err(StorageError) => {
+                error!("Saved keys can't be read, reset the authenticator to use it");
+                (Keys::new(&mut rng), true)
+            }
+        };
+        Ctap {
+            storage,
+            rng,
+            presence,
+            clock,
+            keys,
+            unreadable,
+        }
+    }
+
+    async fn user_presence(&mut self) -> ctap_types::Result<()> {
+        USER_PRESENCE_PENDING.store(true, Ordering::Relaxed);
+        let result = match select3(
+            CANCEL_SIGNAL.wait(),
+            self.presence.confirm(),
+            self.clock.delay(USER_PRESENCE_TIMEOUT),
+        )
+        .await
+        {
+            Either3::First(()) => Err(ctap_types::ctap2::Error::KeepaliveCancel),
+            Either3::Second(()) => Ok(()),
+            Either3::Third(()) => Err(ctap_types::ctap2::Error::UserActionTimeout),
+        };
+        USER_PRESENCE_PENDING.store(false, Ordering::Relaxed);
+        result
+    }
+}
+
+// Credentials are only found for the relying party they were made for,
+// a listed id from another one is treated like a foreign id
+impl<P: Platform> Ctap<P> {
+    fn has_credential_id(
+        &self,
+        credential: &ctap_types::webauthn::PublicKeyCredentialDescriptorRef,
+        rp_id_hash: &[u8; 32],
+    ) -> bool {
+        self.get_credential_id(credential, rp_id_hash).is_some()
+    }
+    fn get_credential_id(
+        &self,
+        credential: &ctap_types::webauthn::PublicKeyCredentialDescriptorRef,
+        rp_id_hash: &[u8; 32],
+    ) -> Option<CtapCredential> {
+        if credential.key_type != "public-key" {
+            return None;
+        }
+        match self.keys.find(&credential.id[..]) {
+            Some(stored) => {
+                Some(stored.clone()).filter(|found| found.rp_id_hash[..] == rp_id_hash[..])
+            }
+            None => self.keys.open(&credential.id[..], rp_id_hash),
+        }
+    }
+}
+
+impl<P: Platform> Ctap1Authenticator for Ctap<P> {
+    fn register(
+        &mut self,
+        _request: &register::Request<'_>,
+    ) -> ctap_types::ctap1::Result<register::Response> {
+        Err(ctap_types::ctap1::Error::InstructionNotSupported)
+    }
+
+    fn authenticate(
+        &mut self,
+        _request: &authenticate::Request<'_>,
+    ) -> ctap_types::ctap1::Result<authenticate::Response> {
+        Err(ctap_types::ctap1::Error::InstructionNotSupported)
+    }
+}
+
+// The Ctap2Authenticator trait from ctap_types is synchronous, which would
+// block the executor while waiting on the user. So the same operations are
+// implemented here as async methods and dispatched by `call`
+impl<P: Platform> Ctap<P> {
+    pub async fn call(
+        &mut self,
+        request: &ctap_types::ctap2::Request,
+        // The parameters as sent, after the command byte
+        params: &[u8],
+    ) -> ctap_types::Result<ctap_types::ctap2::Response> {
+        use ctap_types::ctap2::{Request, Response};
+
+        // Nothing may use the keys until unreadable ones are replaced
+        let keyless = matches!(
+            request,
+            Request::GetInfo | Request::Selection | Request::Reset
+        );
+        if self.unreadable && !keyless {
+            return Err(ctap_types::ctap2::Error::NotAllowed);
+        }
+
+        Ok(match request {
+            Request::GetInfo => Response::GetInfo(self.get_info()),
+            Request::MakeCredential(request) => {
+                Response::MakeCredential(self.make_credential(request, params).await?)
+            }
+            Request::GetAssertion(request) => {
+                Response::GetAssertion(self.get_assertion(request).await?)
+            }
+            Request::GetNextAssertion => Response::GetNextAssertion(self.get_next_assertion()?),
+            Request::Reset => {
+                self.reset().await?;
+                Response::Reset
+            }
+            Request::ClientPin(request) => Response::ClientPin(self.client_pin(request)?),
+            Request::CredentialManagement(request) => {
+                Response::CredentialManagement(self.credential_management(request)?)
+            }
+            Request::Selection => {
+                self.selection().await?;
+                Response::Selection
+            }
+            Request::Vendor(op) => {
+                self.vendor(*op)?;
+                Response::Vendor
+            }
+            _ => return Err(ctap_types::ctap2::Error::InvalidCommand),
+        })
+    }
+
+    fn get_info(&mut self) -> get_info::Response {
+        info!("Getting authenticator info");
+        let versions = [
+            get_info::Version::Fido2_0,
+            get_info::Version::Fido2_1,
+            get_info::Version::Fido2_1Pre,
+            // get_info::Version::U2fV2, // Currently I dont handle Ctap 1
+        ];
+        let resp_builder = get_info::ResponseBuilder {
+            versions: Vec::from_slice(&versions).unwrap(),
+            aaguid: ctap_types::Bytes::from_slice(&AAGUID).unwrap(),
+        };
+        let mut response = resp_builder.build();
+
+        // Only what is actually implemented is advertised. Options that are
+        // left out tell the client the feature isn't supported at all
+        let mut options = get_info::CtapOptions::default();
+        options.rk = true;
+        options.up = true;
+        options.plat = Some(false);
+        options.cred_mgmt = Some(false);
+        options.large_blobs = Some(false);
+        options.always_uv = Some(false);
+        options.authnr_cfg = Some(false);
+        // No pin can be set, so clientPin and the pinUvAuthProtocols
+        // that go with it stay out
+        response.options = Some(options);
+
+        response.max_msg_size = Some(MAX_MSG_SIZE);
+        response.max_creds_in_list = Some(MAX_CREDENTIAL_COUNT_IN_LIST);
+        response.max_cred_id_length = Some(MAX_ID_LEN);
+        response.transports = Some(Vec::from_slice(&[get_info::Transport::Usb]).unwrap());
+        let algorithms = ALGORITHMS.iter().map(|alg| PublicKeyCredentialParameters {
+            alg: alg.id(),
+            key_type: "public-key".into(),
+        });
+        response.algorithms = Some(algorithms.collect());
+        response.firmware_version = Some(FIRMWARE_VERSION);
+        response.remaining_discoverable_credentials =
+            Some(MAX_CREDENTIALS - self.keys.credentials.len());
+
+        response
+    }
+
+    async fn make_credential(
+        &mut self,
+        request: &make_credential::Request<'_>,
+        params: &[u8],
+    ) -> ctap_types::Result<make_credential::Response> {
+        if let Some(options) = &request.options {
+            // User presence is always checked, and there is no way to verify
+            // the user
+            if options.up == Some(false) || options.uv == Some(true) {
+                return Err(ctap_types::ctap2::Error::InvalidOption);
+            }
+        }
+        // No pin can be set, so there is no pin token to check this against
+        if request.pin_auth.is_some() {
+            return Err(ctap_types::ctap2::Error::PinNotSet);
+        }
+
+        let alg = negotiate_algorithm(params)?;
+        let rp_id_hash: [u8; 32] = Sha256::digest(request.rp.id.as_bytes()).into();
+
+        // The relying party already has a credential from this authenticator.
+        // The user still has to confirm, so the reply can't be used to find
+        // out which authenticator is plugged in without them knowing
+        if let Some(list) = &request.exclude_list {
+            if list
+                .iter()
+                .any(|cred| self.has_credential_id(cred, &rp_id_hash))
+            {
+                self.user_presence().await?;
+                return Err(ctap_types::ctap2::Error::CredentialExcluded);
+            }
+        }
+
+        // Only discoverable credentials take up storage, the others
+        // are sealed into their id. A new discoverable credential replaces
+        // the one the relying party already has for the same user
+        let discoverable = request.options.as_ref().and_then(|options| options.rk) == Some(true);
+        let existing = self.keys.credentials.iter().position(|credential| {
+            credential.rp_id_hash[..] == rp_id_hash && credential.user_id[..] == request.user.id[..]
+        });
+        if discoverable && existing.is_none() && self.keys.credentials.is_full() {
+            return Err(ctap_types::ctap2::Error::KeyStoreFull);
+        }
+        self.user_presence().await?;
+
+        let key = SecretKey::generate(alg, &mut self.rng);
+        let id = if discoverable {
+            let mut id = [0; STORED_ID_LEN];
+            self.rng.fill_bytes(&mut id);
+            let credential = CtapCredential {
+                id: Bytes::from_slice(&id).unwrap(),
+                rp_id_hash: Bytes::from_slice(&rp_id_hash).unwrap(),
+                user_id: Bytes::from_slice(&request.user.id).unwrap(),
+                alg: alg.id(),
+                secret: Bytes::from_slice(&key.to_bytes()).unwrap(),
+            };
+            let replaced = match existing {
+                Some(slot) => Some(core::mem::replace(
+                    &mut self.keys.credentials[slot],
+                    credential.clone(),
+                )),
+                // Checked for room above
+                None => {
+                    self.keys.credentials.push(credential.clone()).ok();
+                    None
+                }
+            };
+            self.keys.sign_count += 1;
+            if let Err(e) = self.save_keys().await {
+                // Keep what is in memory the same as what is in storage
+                self.keys.sign_count -= 1;
+                match (existing, replaced) {
+                    (Some(slot), Some(old)) => self.keys.credentials[slot] = old,
+                    _ => drop(self.keys.credentials.pop()),
+                }
+                return Err(e);
+            }
+            info!(
+                "Stored a credential, {} in storage",
+                self.keys.credentials.len()
+            );
+            credential.id
+        } else {
+            let id = self
+                .keys
+                .seal(&mut self.rng, &rp_id_hash, alg.id(), &key.to_bytes());
+            self.bump_sign_count().await?;
+            id
+        };
+
+        let mut auth_data = AuthData::new(
+            &rp_id_hash,
+            FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL,
+            self.keys.sign_count,
+        );
+        auth_data.attest(&AAGUID, &id[..], &key)?;
+
+        // Packed self attestation, signed by the credential itself
+        let signature = self.sign(&key, &auth_data, &request.client_data_hash[..])?;
+        let att_stmt = make_credential::PackedAttestationStatement {
+            alg: alg.id(),
+            sig: Bytes::from_slice(&signature).unwrap(),
+            x5c: None,
+        };
+
+        let mut response = make_credential::ResponseBuilder {
+            fmt: "packed".into(),
+            auth_data: Bytes::from_slice(auth_data.as_bytes()).unwrap(),
+        }
+        .build();
+        response.att_stmt = Some(make_credential::AttestationStatement::Packed(att_stmt));
+        Ok(response)
+    }
+
+    // Counts a signature, only once the new count has been saved
+    async fn bump_sign_count(&mut self) -> ctap_types::Result<()> {
+        self.keys.sign_count += 1;
+        self.save_keys()
+            .await
+            .inspect_err(|_| self.keys.sign_count -= 1)
+    }
+
+    async fn save_keys(&mut self) -> ctap_types::Result<()> {
+        self.keys
+            .save(&mut self.storage)
+            .await
+            .map_err(|_| ctap_types::ctap2::Error::Other)
+    }
+
+    async fn get_assertion(
+        &mut self,
+        request: &get_assertion::Request<'_>,
+    ) -> ctap_types::Result<get_assertion::Response> {
+        let mut user_present = true;
+        if let Some(options) = &request.options {
+            if options.uv == Some(true) {
+                return Err(ctap_types::ctap2::Error::InvalidOption);
+            }
+            // Lets the platform check a credential exists without bothering
+            // the user
+            user_present = options.up != Some(false);
+        }
+        if request.pin_auth.is_some() {
+            return Err(ctap_types::ctap2::Error::PinNotSet);
+        }
+
+        let rp_id_hash: [u8; 32] = Sha256::digest(request.rp_id.as_bytes()).into();
+        let credential = match &request.allow_list {
+            Some(list) if !list.is_empty() => list
+                .iter()
+                .find_map(|listed| self.get_credential_id(listed, &rp_id_hash)),
+            // The newest discoverable credential for the relying party
+            _ => self
+                .keys
+                .credentials
+                .iter()
+                .rev()
+                .find(|credential| credential.rp_id_hash[..] == rp_id_hash)
+                .cloned(),
+        };
+        let credential = credential.ok_or(ctap_types::ctap2::Error::NoCredentials)?;
+        let key = Algorithm::from_id(credential.alg.into())
+            .and_then(|alg| SecretKey::from_bytes(alg, &credential.secret))
+            .ok_or(ctap_types::ctap2::Error::Other)?;
+
+        let mut flags = 0;
+        if user_present {
+            self.user_presence().await?;
+            flags |= FLAG_USER_PRESENT;
+        }
+        self.bump_sign_count().await?;
+
+        let auth_data = AuthData::new(&rp_id_hash, flags, self.keys.sign_count);
+        let signature = self.sign(&key, &auth_data, &request.client_data_hash[..])?;
+        let mut response = get_assertion::ResponseBuilder {
+            credential: PublicKeyCredentialDescriptor {
+                id: Bytes::from_slice(&credential.id).unwrap(),
+                key_type: "public-key".into(),
+            },
+            auth_data: Bytes::from_slice(auth_data.as_bytes()).unwrap(),
+            signature: Bytes::from_slice(&signature).unwrap(),
+        }
+        .build();
+        // Only stored credentials have a user
+        if !credential.user_id.is_empty() {
+            response.user = Some(PublicKeyCredentialUserEntity {
+                id: credential.user_id,
+                icon: None,
+                name: None,
+                display_name: None,
+            });
+        }
+        Ok(response)
+    }
+
+    // Signs authenticatorData followed by the client data hash, the
+    // signature webauthn checks for both attestations and assertions
+    fn sign(
+        &self,
+        key: &SecretKey,
+        auth_data: &AuthData,
+        client_data_hash: &[u8],
+    ) -> ctap_types::Result<Signature> {
+        let mut signed: Vec<u8, { AUTH_DATA_LEN + 32 }> = Vec::new();
+        signed
+            .extend_from_slice(auth_data.as_bytes())
+            .and_then(|_| signed.extend_from_slice(client_data_hash))
+            .map_err(|_| ctap_types::ctap2::Error::InvalidLength)?;
+        Ok(key.sign(&signed))
+    }
+
+    fn get_next_assertion(&mut self) -> ctap_types::Result<get_assertion::Response> {
+        // getAssertion only ever offers one credential
+        Err(ctap_types::ctap2::Error::NotAllowed)
+    }
+
+    async fn reset(&mut self) -> ctap_types::Result<()> {
+        if self.clock.now() > Instant::from_ticks(0) + RESET_WINDOW {
+            return Err(ctap_types::ctap2::Error::NotAllowed);
+        }
+        self.user_presence().await?;
+
+        info!("Resetting the authenticator");
+        let keys = Keys::new(&mut self.rng);
+        keys.save(&mut self.storage)
+            .await
+            .map_err(|_| ctap_types::ctap2::Error::Other)?;
+        self.keys = keys;
+        self.unreadable = false;
+        Ok(())
+    }
+
+    // Pins are not supported, so getInfo doesn't list the clientPin option
+    fn client_pin(
+        &mut self,
+        _request: &client_pin::Request,
+    ) -> ctap_types::Result<client_pin::Response> {
+        Err(ctap_types::ctap2::Error::InvalidCommand)
+    }
+
+    fn credential_management(
+        &mut self,
+        _request: &credential_management::Request,
+    ) -> ctap_types::Result<credential_management::Response> {
+        Err(ctap_types::ctap2::Error::InvalidCommand)
+    }
+
+    async fn selection(&mut self) -> ctap_types::Result<()> {
+        self.user_presence().await
+    }
+
+    fn vendor(&mut self, _op: VendorOperation) -> ctap_types::Result<()> {
+        Err(ctap_types::ctap2::Error::InvalidCommand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ctap_types::Vec;
+    use embassy_futures::block_on;
+    use p256::ecdsa::signature::Verifier;
+    use sha2::{Digest, Sha256};
+
+    use super::{parse_aaguid, Ctap};
+    use crate::auth_data::{CREDENTIAL_ID_LEN, FLAG_ATTESTED_CREDENTIAL, FLAG_USER_PRESENT};
+    use crate::cbor::{Reader, Writer};
+    use crate::credential::Algorithm;
+    use crate::dispatch::{Buffer, Dispatcher, Request};
+    use crate::host::{AlwaysPresent, Host, MemoryStorage, XorShiftRng};
+    use crate::keys::{STORED_ID_LEN, WRAPPED_ID_LEN};
+    use crate::platform::{Storage, SystemClock};
+
+    const AAGUID: [u8; 16] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ];
+
+    // Authenticator API command bytes
+    const MAKE_CREDENTIAL: u8 = 0x01;
+    const GET_ASSERTION: u8 = 0x02;
+    const GET_INFO: u8 = 0x04;
+    const RESET: u8 = 0x07;
+
+    const RP_ID: &str = "example.com";
+    const USER_ID: [u8; 4] = [1, 2, 3, 4];
+    const CLIENT_DATA_HASH: [u8; 32] = [0x44; 32];
+
+    type Authenticator = Dispatcher<Host<XorShiftRng>>;
+    type Params = Vec<u8, 512>;
+
+    fn authenticator(storage: MemoryStorage) -> Authenticator {
+        let ctap = Ctap::<Host<XorShiftRng>>::new(
+            storage,
+            XorShiftRng::default(),
+            AlwaysPresent,
+            SystemClock,
+        );
+        Dispatcher::new(block_on(ctap), &[])
+    }
+
+    // Runs an authenticator API command, returns the status and the cbor after it
+    fn call(authenticator: &mut Authenticator, command: u8, params: &[u8]) -> (u8, Buffer) {
+        let mut request = Buffer::new();
+        request.push(command).unwrap();
+        request.extend_from_slice(params).unwrap();
+        let mut response = Buffer::new();
+        block_on(authenticator.dispatch(Request::Cbor(&request), &mut response)).unwrap();
+        let (status, cbor) = response.split_first().unwrap();
+        (*status, Buffer::from_slice(cbor).unwrap())
+    }
+
+    // Requests are written with Writer, plus short texts, maps and arrays
+    fn text(params: &mut Params, text: &str) {
+        params.push(0x60 | text.len() as u8).unwrap();
+        params.extend_from_slice(text.as_bytes()).unwrap();
+    }
+
+    fn map(params: &mut Params, len: usize) {
+        params.push(0xa0 | len as u8).unwrap();
+    }
+
+    fn array(params: &mut Params, len: usize) {
+        params.push(0x80 | len as u8).unwrap();
+    }
+
+    // An entry of an exclude or allow list
+    fn descriptor(params: &mut Params, id: &[u8]) {
+        map(params, 2);
+        text(params, "id");
+        Writer::new(params).bytes(id).unwrap();
+        text(params, "type");
+        text(params, "public-key");
+    }
+
+    fn make_credential(
+        alg: Algorithm,
+        rp_id: &str,
+        discoverable: bool,
+        exclude: &[&[u8]],
+    ) -> Params {
+        let mut params = Params::new();
+        map(
+            &mut params,
+            4 + !exclude.is_empty() as usize + discoverable as usize,
+        );
+        Writer::new(&mut params).int(1).unwrap();
+        Writer::new(&mut params).bytes(&CLIENT_DATA_HASH).unwrap();
+        Writer::new(&mut params).int(2).unwrap();
+        map(&mut params, 1);
+        text(&mut params, "id");
+        text(&mut params, rp_id);
+        Writer::new(&mut params).int(3).unwrap();
+        map(&mut params, 1);
+        text(&mut params, "id");
+        Writer::new(&mut params).bytes(&USER_ID).unwrap();
+        Writer::new(&mut params).int(4).unwrap();
+        array(&mut params, 1);
+        map(&mut params, 2);
+        text(&mut params, "alg");
+        Writer::new(&mut params).int(alg.id().into()).unwrap();
+        text(&mut params, "type");
+        text(&mut params, "public-key");
+        if !exclude.is_empty() {
+            Writer::new(&mut params).int(5).unwrap();
+            array(&mut params, exclude.len());
+            for id in exclude {
+                descriptor(&mut params, id);
+            }
+        }
+        if discoverable {
+            Writer::new(&mut params).int(7).unwrap();
+            map(&mut params, 1);
+            text(&mut params, "rk");
+            params.push(0xf5).unwrap();
+        }
+        params
+    }
+
+    // Signing in to RP_ID with the credential `id`
+    fn get_assertion(id: &[u8]) -> Params {
+        let mut params = Params::new();
+        map(&mut params, 3);
+        Writer::new(&mut params).int(1).unwrap();
+        text(&mut params, RP_ID);
+        Writer::new(&mut params).int(2).unwrap();
+        Writer::new(&mut params).bytes(&CLIENT_DATA_HASH).unwrap();
+        Writer::new(&mut params).int(3).unwrap();
+        array(&mut params, 1);
+        descriptor(&mut params, id);
+        params
+    }
+
+    // authenticatorData and the signature from a makeCredential reply,
+    // which has to be a packed attestation made with `alg`
+    fn attestation(alg: Algorithm, reply: &[u8]) -> (&[u8], &[u8]) {
+        let mut reply = Reader::new(reply);
+        let (mut auth_data, mut sig) = (None, None);
+        for _ in 0..reply.map().unwrap() {
+            match reply.int().unwrap() {
+                1 => assert_eq!(reply.text().unwrap(), "packed"),
+                2 => auth_data = Some(reply.bytes().unwrap()),
+                3 => {
+                    for _ in 0..reply.map().unwrap() {
+                        match reply.text().unwrap() {
+                            "alg" => assert_eq!(reply.int().unwrap(), i64::from(alg.id())),
+                            "sig" => sig = Some(reply.bytes().unwrap()),
+                            _ => reply.skip().unwrap(),
+                        }
+                    }
+                }
+                _ => reply.skip().unwrap(),
+            }
+        }
+        (auth_data.unwrap(), sig.unwrap())
+    }
+
+    // The credential id, authenticatorData and signature from a getAssertion reply
+    fn assertion(reply: &[u8]) -> (&[u8], &[u8], &[u8]) {
+        let mut reply = Reader::new(reply);
+        let (mut id, mut auth_data, mut signature) = (None, None, None);
+        for _ in 0..reply.map().unwrap() {
+            match reply.int().unwrap() {
+                1 => {
+                    for _ in 0..reply.map().unwrap() {
+                        match reply.text().unwrap() {
+                            "id" => id = Some(reply.bytes().unwrap()),
+                            _ => reply.skip().unwrap(),
+                        }
+                    }
+                }
+                2 => auth_data = Some(reply.bytes().unwrap()),
+                3 => signature = Some(reply.bytes().unwrap()),
+                _ => reply.skip().unwrap(),
+            }
+        }
+        (id.unwrap(), auth_data.unwrap(), signature.unwrap())
+    }
+
+    // Checks the rpIdHash, flags and signCount at the start of authenticatorData
+    fn check_auth_data(auth_data: &[u8], flags: u8, sign_count: u32) {
+        let rp_id_hash: [u8; 32] = Sha256::digest(RP_ID).into();
+        assert_eq!(auth_data[..32], rp_id_hash);
+        assert_eq!(auth_data[32], flags);
+        assert_eq!(auth_data[33..37], sign_count.to_be_bytes());
+    }
+
+    // The credential id and COSE key from the attested credential data
+    fn attested_credential(auth_data: &[u8]) -> (&[u8], &[u8]) {
+        let (aaguid, rest) = auth_data[37..].split_at(16);
+        assert_eq!(aaguid, super::AAGUID);
+        let (len, rest) = rest.split_at(2);
+        rest.split_at(u16::from_be_bytes([len[0], len[1]]) as usize)
+    }
+
+    // A credential's public key, read back from its COSE key
+    enum PublicKey {
+        Es256(p256::ecdsa::VerifyingKey),
+        Es256k(k256::ecdsa::VerifyingKey),
+        EdDsa(ed25519_dalek::VerifyingKey),
+    }
+
+    impl PublicKey {
+        fn parse(alg: Algorithm, cose: &[u8]) -> Self {
+            let mut cose = Reader::new(cose);
+            let (mut kty, mut cose_alg, mut crv, mut x, mut y) = (None, None, None, None, None);
+            for _ in 0..cose.map().unwrap() {
+                match cose.int().unwrap() {
+                    1 => kty = Some(cose.int().unwrap()),
+                    3 => cose_alg = Some(cose.int().unwrap()),
+                    -1 => crv = Some(cose.int().unwrap()),
+                    -2 => x = Some(cose.bytes().unwrap()),
+                    -3 => y = Some(cose.bytes().unwrap()),
+                    label => panic!("unexpected COSE key parameter {}", label),
+                }
+            }
+            assert_eq!(cose_alg, Some(i64::from(alg.id())));
+
+            // EC2 keys are uncompressed SEC1 points, 0x04 | x | y
+            let mut point: Vec<u8, 65> = Vec::new();
+            if let (Some(x), Some(y)) = (x, y) {
+                point.push(0x04).unwrap();
+                point.extend_from_slice(x).unwrap();
+                point.extend_from_slice(y).unwrap();
+            }
+            match (kty, crv) {
+                (Some(2), Some(1)) => {
+                    PublicKey::Es256(p256::ecdsa::VerifyingKey::from_sec1_bytes(&point).unwrap())
+                }
+                (Some(2), Some(8)) => {
+                    PublicKey::Es256k(k256::ecdsa::VerifyingKey::from_sec1_bytes(&point).unwrap())
+                }
+                (Some(1), Some(6)) => {
+                    let x = x.unwrap().try_into().unwrap();
+                    PublicKey::EdDsa(ed25519_dalek::VerifyingKey::from_bytes(x).unwrap())
+                }
+                other => panic!("unexpected COSE key type and curve {:?}", other),
+            }
+        }
+
+        // Checks a signature over authenticatorData and CLIENT_DATA_HASH
+        fn verify(&self, auth_data: &[u8], signature: &[u8]) -> bool {
+            let mut signed: Vec<u8, 512> = Vec::from_slice(auth_data).unwrap();
+            signed.extend_from_slice(&CLIENT_DATA_HASH).unwrap();
+            match self {
+                PublicKey::Es256(key) => p256::ecdsa::Signature::from_der(signature)
+                    .is_ok_and(|signature| key.verify(&signed, &signature).is_ok()),
+                PublicKey::Es256k(key) => k256::ecdsa::Signature::from_der(signature)
+                    .is_ok_and(|signature| key.verify(&signed, &signature).is_ok()),
+                PublicKey::EdDsa(key) => ed25519_dalek::Signature::from_slice(signature)
+                    .is_ok_and(|signature| key.verify(&signed, &signature).is_ok()),
+            }
+        }
+    }
+
+    // Registers a credential made with `alg` and signs in with it, checking
+    // everything a relying party would
+    fn register_and_sign_in(alg: Algorithm) {
+        let mut authenticator = authenticator(MemoryStorage::default());
+        let params = make_credential(alg, RP_ID, false, &[]);
+        let (status, reply) = call(&mut authenticator, MAKE_CREDENTIAL, &params);
+        assert_eq!(status, 0);
+        let (auth_data, signature) = attestation(alg, &reply);
+        check_auth_data(auth_data, FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL, 1);
+        let (id, cose_key) = attested_credential(auth_data);
+        assert_eq!(id.len(), WRAPPED_ID_LEN);
+        let key = PublicKey::parse(alg, cose_key);
+        // Self attestation, signed by the credential itself
+        assert!(key.verify(auth_data, signature));
+
+        let (status, reply) = call(&mut authenticator, GET_ASSERTION, &get_assertion(id));
+        assert_eq!(status, 0);
+        let (asserted, auth_data, signature) = assertion(&reply);
+        assert_eq!(asserted, id);
+        assert_eq!(auth_data.len(), 37);
+        check_auth_data(auth_data, FLAG_USER_PRESENT, 2);
+        assert!(key.verify(auth_data, signature));
+    }
+
+    #[test]
+    fn es256_credentials_sign_in() {
+        register_and_sign_in(Algorithm::Es256);
+    }
+
+    #[test]
+    fn es256k_credentials_sign_in() {
+        register_and_sign_in(Algorithm::Es256k);
+    }
+
+    #[test]
+    fn eddsa_credentials_sign_in() {
+        register_and_sign_in(Algorithm::EdDsa);
+    }
+
+    // Makes a credential for `rp_id` and returns its id
+    fn register(
+        authenticator: &mut Authenticator,
+        rp_id: &str,
+        discoverable: bool,
+    ) -> Vec<u8, CREDENTIAL_ID_LEN> {
+        let params = make_credential(Algorithm::Es256, rp_id, discoverable, &[]);
+        let (status, reply) = call(authenticator, MAKE_CREDENTIAL, &params);
+        assert_eq!(status, 0);
+        let (auth_data, _) = attestation(Algorithm::Es256, &reply);
+        let (id, _) = attested_credential(auth_data);
+        let len = match discoverable {
+            true => STORED_ID_LEN,
+            false => WRAPPED_ID_LEN,
+        };
+        assert_eq!(id.len(), len);
+        Vec::from_slice(id).unwrap()
+    }
+
+    #[test]
+    fn registered_credentials_are_excluded() {
+        let mut authenticator = authenticator(MemoryStorage::default());
+        // Stored and sealed, listed after an id that isn't this device's
+        for discoverable in [true, false] {
+            let id = register(&mut authenticator, RP_ID, discoverable);
+            let params = make_credential(Algorithm::Es256, RP_ID, false, &[&[0x55; 16], &id]);
+            let (status, _) = call(&mut authenticator, MAKE_CREDENTIAL, &params);
+            assert_eq!(status, ctap_types::ctap2::Error::CredentialExcluded as u8);
+        }
+    }
+
+    #[test]
+    fn other_relying_parties_credentials_are_not_excluded() {
+        let mut authenticator = authenticator(MemoryStorage::default());
+        for discoverable in [true, false] {
+            let id = register(&mut authenticator, "other.example", discoverable);
+            let params = make_credential(Algorithm::Es256, RP_ID, false, &[&id]);
+            assert_eq!(call(&mut authenticator, MAKE_CREDENTIAL, &params).0, 0);
+        }
+    }
+
+    #[test]
+    fn unreadable_keys_wait_for_a_reset() {
+        let mut storage = MemoryStorage::default();
+        block_on(storage.save(&[0xff, 0x00])).unwrap();
+        let mut authenticator = authenticator(storage);
+
+        let params = make_credential(Algorithm::Es256, RP_ID, false, &[]);
+        let (status, _) = call(&mut authenticator, MAKE_CREDENTIAL, &params);
+        assert_eq!(status, ctap_types::ctap2::Error::NotAllowed as u8);
+        assert_eq!(call(&mut authenticator, GET_INFO, &[]).0, 0);
+
+        assert_eq!(call(&mut authenticator, RESET, &[]).0, 0);
+        assert_eq!(call(&mut authenticator, MAKE_CREDENTIAL, &params).0, 0);
+    }
+
+    #[test]
+    fn parse_aaguid_reads_uuids() {
+        assert_eq!(parse_aaguid("01234567-89ab-cdef-0123-456789abcdef"), AAGUID);
+        assert_eq!(parse_aaguid("0123456789ABCDEF0123456789abcdef"), AAGUID);
+    }
+
+    #[test]
+    #[should_panic(expected = "shorter")]
+    fn parse_aaguid_rejects_short_uuids() {
+        parse_aaguid("01234567-89ab-cdef-0123-456789abcde");
+    }
+
+    #[test]
+    #[should_panic(expected = "longer")]
+    fn parse_aaguid_rejects_long_uuids() {
+        parse_aaguid("01234567-89ab-cdef-0123-456789abcdef0");
+    }
+
+    #[test]
+    #[should_panic(expected = "has to be a uuid")]
+    fn parse_aaguid_rejects_other_characters() {
+        parse_aaguid("01234567-89ab-cdef-0123-456789abcdeg");
+    }
+}
@@ -0,0 +1,1060 @@
+// CTAPHID framing as described in the FIDO CTAP spec (section 11.2).
+// Every HID report is a 64 byte packet, either an initialization packet:
+//   CID (4) | CMD (1, high bit set) | BCNTH (1) | BCNTL (1) | DATA (57)
+// or a continuation packet:
+//   CID (4) | SEQ (1, high bit clear) | DATA (59)
+// A message is one init packet followed by up to 128 continuation packets.
+
+use ctap_types::Vec;
+use defmt::*;
+use embassy_time::{Duration, Instant};
+
+use crate::dispatch::{self, Dispatcher, Request, MAX_MSG_SIZE};
+use crate::platform::Platform;
+
+pub const PACKET_SIZE: usize = 64;
+pub const INIT_HEADER_LEN: usize = 7;
+pub const CONT_HEADER_LEN: usize = 5;
+pub const INIT_DATA_LEN: usize = PACKET_SIZE - INIT_HEADER_LEN;
+pub const CONT_DATA_LEN: usize = PACKET_SIZE - CONT_HEADER_LEN;
+pub const MAX_SEQ: u8 = 0x7f;
+
+// The largest message that fits in one init and 128 continuation packets
+pub const MAX_MESSAGE_LEN: usize = INIT_DATA_LEN + (MAX_SEQ as usize + 1) * CONT_DATA_LEN;
+// Dispatcher replies are framed as they are
+const _: () = core::assert!(MAX_MSG_SIZE == MAX_MESSAGE_LEN);
+
+pub const BROADCAST_CID: u32 = 0xffff_ffff;
+// Opening more channels than this evicts the one that was idle the longest
+pub const MAX_CHANNELS: usize = 8;
+
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const CAPABILITY_WINK: u8 = 0x01;
+pub const CAPABILITY_CBOR: u8 = 0x04;
+pub const CAPABILITY_NMSG: u8 = 0x08;
+// Ctap1 (U2F) messages are not handled, so CTAPHID_MSG is not advertised
+// and is answered with ERR_INVALID_CMD
+pub const CAPABILITIES: u8 = CAPABILITY_WINK | CAPABILITY_CBOR | CAPABILITY_NMSG;
+
+// Major, minor and build number reported in CTAPHID_INIT
+pub const DEVICE_VERSION: [u8; 3] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+const INIT_NONCE_LEN: usize = 8;
+const INIT_RESPONSE_LEN: usize = 17;
+
+// Longest a channel may hold CTAPHID_LOCK, in seconds
+const MAX_LOCK_SECONDS: u8 = 10;
+// A partial message is dropped if the next packet doesn't show up in time
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_millis(500);
+
+const fn parse_version(version: &str) -> u8 {
+    let digits = version.as_bytes();
+    let mut value: u8 = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+pub type Packet = [u8; PACKET_SIZE];
+pub type Payload = Vec<u8, MAX_MESSAGE_LEN>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Command {
+    Ping,
+    Msg,
+    Lock,
+    Init,
+    Wink,
+    Cbor,
+    Cancel,
+    Keepalive,
+    Error,
+    Vendor(u8),
+}
+
+pub const VENDOR_COMMAND_FIRST: u8 = 0x40;
+pub const VENDOR_COMMAND_LAST: u8 = 0x7f;
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(cmd: u8) -> Result<Self, u8> {
+        Ok(match cmd {
+            0x01 => Command::Ping,
+            0x03 => Command::Msg,
+            0x04 => Command::Lock,
+            0x06 => Command::Init,
+            0x08 => Command::Wink,
+            0x10 => Command::Cbor,
+            0x11 => Command::Cancel,
+            0x3b => Command::Keepalive,
+            0x3f => Command::Error,
+            VENDOR_COMMAND_FIRST..=VENDOR_COMMAND_LAST => Command::Vendor(cmd),
+            _ => return Err(cmd),
+        })
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(cmd: Command) -> u8 {
+        match cmd {
+            Command::Ping => 0x01,
+            Command::Msg => 0x03,
+            Command::Lock => 0x04,
+            Command::Init => 0x06,
+            Command::Wink => 0x08,
+            Command::Cbor => 0x10,
+            Command::Cancel => 0x11,
+            Command::Keepalive => 0x3b,
+            Command::Error => 0x3f,
+            Command::Vendor(cmd) => cmd,
+        }
+    }
+}
+
+/// Error codes sent in CTAPHID_ERROR
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum ErrorCode {
+    InvalidCmd = 0x01,
+    InvalidPar = 0x02,
+    InvalidLen = 0x03,
+    InvalidSeq = 0x04,
+    MsgTimeout = 0x05,
+    ChannelBusy = 0x06,
+    InvalidChannel = 0x0b,
+}
+
+impl From<dispatch::Error> for ErrorCode {
+    fn from(error: dispatch::Error) -> Self {
+        match error {
+            dispatch::Error::InvalidCommand => ErrorCode::InvalidCmd,
+            dispatch::Error::InvalidParameter => ErrorCode::InvalidPar,
+            dispatch::Error::InvalidLength => ErrorCode::InvalidLen,
+        }
+    }
+}
+
+/// Status byte of a CTAPHID_KEEPALIVE
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum KeepaliveStatus {
+    Processing = 1,
+    UpNeeded = 2,
+}
+
+impl KeepaliveStatus {
+    pub fn packet(self, cid: u32) -> Packet {
+        let status = [self as u8];
+        let mut keepalive = Fragments::new(cid, Command::Keepalive.into(), &status);
+        // The first fragment always exists, even for empty messages
+        unwrap!(keepalive.next())
+    }
+}
+
+/// A parsed view of a single 64 byte CTAPHID report
+#[derive(Debug, Format)]
+pub enum Frame<'a> {
+    Init {
+        cid: u32,
+        cmd: u8,
+        len: u16,
+        data: &'a [u8],
+    },
+    Cont {
+        cid: u32,
+        seq: u8,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(packet: &'a Packet) -> Self {
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if packet[4] & 0x80 != 0 {
+            Frame::Init {
+                cid,
+                cmd: packet[4] & 0x7f,
+                len: u16::from_be_bytes([packet[5], packet[6]]),
+                data: &packet[INIT_HEADER_LEN..],
+            }
+        } else {
+            Frame::Cont {
+                cid,
+                seq: packet[4],
+                data: &packet[CONT_HEADER_LEN..],
+            }
+        }
+    }
+
+    pub fn cid(&self) -> u32 {
+        match self {
+            Frame::Init { cid, .. } | Frame::Cont { cid, .. } => *cid,
+        }
+    }
+}
+
+/// A complete CTAPHID message, either reassembled from the host's packets
+/// or a response waiting to be split into packets
+pub struct Message {
+    pub cid: u32,
+    pub cmd: u8,
+    pub data: Payload,
+}
+
+impl Message {
+    pub fn new(cid: u32, cmd: Command) -> Self {
+        Message {
+            cid,
+            cmd: cmd.into(),
+            data: Vec::new(),
+        }
+    }
+
+    pub fn error(cid: u32, code: ErrorCode) -> Self {
+        let mut message = Message::new(cid, Command::Error);
+        unwrap!(message.data.push(code as u8));
+        message
+    }
+
+    pub fn packets(&self) -> Fragments<'_> {
+        Fragments::new(self.cid, self.cmd, &self.data)
+    }
+
+    /// The request this message carries for the authenticator, None for
+    /// commands the transport answers itself or doesn't support
+    pub fn request(&self) -> Option<Request<'_>> {
+        match Command::try_from(self.cmd) {
+            Ok(Command::Cbor) => Some(Request::Cbor(&self.data)),
+            Ok(Command::Vendor(cmd)) => Some(Request::Vendor(cmd, &self.data)),
+            // Ctap1 is not offered over ctaphid, see CAPABILITIES
+            _ => None,
+        }
+    }
+}
+
+/// Runs a request message through the dispatcher and returns the reply,
+/// sent under the same command or as CTAPHID_ERROR if it failed
+pub async fn dispatch<P: Platform>(dispatcher: &mut Dispatcher<P>, request: &Message) -> Message {
+    let mut response = Message {
+        cid: request.cid,
+        cmd: request.cmd,
+        data: Vec::new(),
+    };
+    let result = match request.request() {
+        Some(dispatched) => dispatcher.dispatch(dispatched, &mut response.data).await,
+        None => Err(dispatch::Error::InvalidCommand),
+    };
+    if let Err(error) = result {
+        warn!("Ctaphid command {:#x} failed: {}", request.cmd, error);
+        response = Message::error(request.cid, error.into());
+    }
+    response
+}
+
+/// Splits a message into one init packet followed by as many continuation
+/// packets as are needed to hold the rest of the data
+pub struct Fragments<'a> {
+    cid: u32,
+    cmd: u8,
+    data: &'a [u8],
+    seq: Option<u8>,
+}
+
+impl<'a> Fragments<'a> {
+    fn new(cid: u32, cmd: u8, data: &'a [u8]) -> Self {
+        // Anything past the largest message can not be framed
+        let data = &data[..data.len().min(MAX_MESSAGE_LEN)];
+        Fragments {
+            cid,
+            cmd,
+            data,
+            seq: None,
+        }
+    }
+}
+
+impl Iterator for Fragments<'_> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&self.cid.to_be_bytes());
+
+        let (header_len, chunk_len) = match self.seq {
+            None => {
+                packet[4] = self.cmd | 0x80;
+                packet[5..INIT_HEADER_LEN].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+                self.seq = Some(0);
+                (INIT_HEADER_LEN, self.data.len().min(INIT_DATA_LEN))
+            }
+            Some(_) if self.data.is_empty() => return None,
+            Some(seq) => {
+                packet[4] = seq;
+                self.seq = Some(seq + 1);
+                (CONT_HEADER_LEN, self.data.len().min(CONT_DATA_LEN))
+            }
+        };
+
+        let (chunk, rest) = self.data.split_at(chunk_len);
+        packet[header_len..header_len + chunk_len].copy_from_slice(chunk);
+        self.data = rest;
+        Some(packet)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum FramingError {
+    // Declared length is bigger than what can be reassembled
+    InvalidLen,
+    // Continuation packet is out of order
+    InvalidSeq,
+    // Continuation packet without a preceding init packet
+    UnexpectedCont,
+    // Packet for another channel arrived while a message is being reassembled
+    Busy,
+}
+
+/// Glues init and continuation packets back into a single message.
+/// Only one message can be in flight at a time, like the spec requires.
+pub struct Assembler {
+    message: Message,
+    expected: usize,
+    next_seq: u8,
+    receiving: bool,
+    deadline: Instant,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub const fn new() -> Self {
+        Assembler {
+            message: Message {
+                cid: 0,
+                cmd: 0,
+                data: Vec::new(),
+            },
+            expected: 0,
+            next_seq: 0,
+            receiving: false,
+            deadline: Instant::from_ticks(0),
+        }
+    }
+
+    /// Returns the cid of the message currently being reassembled
+    pub fn pending(&self) -> Option<u32> {
+        self.receiving.then_some(self.message.cid)
+    }
+
+    /// Returns when the message being reassembled expires, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.receiving.then_some(self.deadline)
+    }
+
+    /// Drops any partially received message
+    pub fn reset(&mut self) {
+        self.receiving = false;
+        self.expected = 0;
+        self.next_seq = 0;
+        self.message.data.clear();
+    }
+
+    /// Feeds one packet into the assembler and returns the message once the
+    /// last packet for it has been received
+    pub fn push(&mut self, frame: Frame<'_>) -> Result<Option<&Message>, FramingError> {
+        match frame {
+            Frame::Init {
+                cid,
+                cmd,
+                len,
+                data,
+            } => {
+                if self.receiving && cid != self.message.cid {
+                    return Err(FramingError::Busy);
+                }
+                // A new init packet on the same channel aborts the old message
+                self.reset();
+
+                let len = len as usize;
+                if len > MAX_MESSAGE_LEN {
+                    return Err(FramingError::InvalidLen);
+                }
+
+                self.message.cid = cid;
+                self.message.cmd = cmd;
+                self.expected = len;
+                self.receiving = true;
+                self.extend(data);
+            }
+            Frame::Cont { cid, seq, data } => {
+                if !self.receiving {
+                    return Err(FramingError::UnexpectedCont);
+                }
+                if cid != self.message.cid {
+                    return Err(FramingError::Busy);
+                }
+                if seq != self.next_seq {
+                    self.reset();
+                    return Err(FramingError::InvalidSeq);
+                }
+                self.next_seq += 1;
+                self.extend(data);
+            }
+        }
+
+        self.deadline = Instant::now() + MESSAGE_TIMEOUT;
+        if self.message.data.len() == self.expected {
+            self.receiving = false;
+            Ok(Some(&self.message))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        let remaining = self.expected - self.message.data.len();
+        let data = &data[..remaining.min(data.len())];
+        // The length was checked against the buffer size when the message started
+        unwrap!(self.message.data.extend_from_slice(data));
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    cid: u32,
+    last_used: u32,
+}
+
+/// Bounded table of the channels hosts have opened with CTAPHID_INIT
+pub struct Channels {
+    table: [Option<Channel>; MAX_CHANNELS],
+    next_cid: u32,
+    tick: u32,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels::new()
+    }
+}
+
+impl Channels {
+    pub const fn new() -> Self {
+        Channels {
+            table: [None; MAX_CHANNELS],
+            next_cid: 1,
+            tick: 0,
+        }
+    }
+
+    pub fn contains(&self, cid: u32) -> bool {
+        self.table
+            .iter()
+            .flatten()
+            .any(|channel| channel.cid == cid)
+    }
+
+    /// Marks a channel as recently used, returns false if it was never allocated
+    pub fn touch(&mut self, cid: u32) -> bool {
+        self.tick = self.tick.wrapping_add(1);
+        let tick = self.tick;
+        match self.table.iter_mut().flatten().find(|c| c.cid == cid) {
+            Some(channel) => {
+                channel.last_used = tick;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hands out a new unique cid, reusing the least recently used slot when
+    /// full. Channels `in_use` says are still needed are never evicted
+    pub fn allocate(&mut self, in_use: impl Fn(u32) -> bool) -> u32 {
+        let cid = loop {
+            let cid = self.next_cid;
+            self.next_cid = self.next_cid.wrapping_add(1);
+            if cid != 0 && cid != BROADCAST_CID && !self.contains(cid) {
+                break cid;
+            }
+        };
+
+        self.tick = self.tick.wrapping_add(1);
+        let tick = self.tick;
+        let slot = self
+            .table
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or_else(|| {
+                // Every slot is taken, evict the channel idle for the longest.
+                // Only a few channels are ever in use, so there is always one
+                self.table
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(slot, c)| c.map(|c| (slot, c)))
+                    .filter(|(_, c)| !in_use(c.cid))
+                    .max_by_key(|(_, c)| tick.wrapping_sub(c.last_used))
+                    .map_or(0, |(slot, _)| slot)
+            });
+        self.table[slot] = Some(Channel {
+            cid,
+            last_used: tick,
+        });
+        cid
+    }
+}
+
+/// A reply to the host on a channel, before it is split into packets
+pub struct Response<'a> {
+    pub cid: u32,
+    pub cmd: Command,
+    pub data: &'a [u8],
+}
+
+impl Response<'_> {
+    pub fn to_message(&self) -> Message {
+        let mut message = Message::new(self.cid, self.cmd);
+        let len = self.data.len().min(MAX_MESSAGE_LEN);
+        unwrap!(message.data.extend_from_slice(&self.data[..len]));
+        message
+    }
+}
+
+/// Tells requests handed out in `Event::Request` apart, later requests
+/// get larger ids
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct RequestId(u32);
+
+impl RequestId {
+    /// Returns true if this request was handed out no later than `other`
+    pub fn up_to(self, other: RequestId) -> bool {
+        // Ids wrap around, far apart ones never both exist
+        other.0.wrapping_sub(self.0) <= u32::MAX / 2
+    }
+}
+
+pub enum Event<'a> {
+    // A complete message for the authenticator
+    Request(RequestId, &'a Message),
+    // A reply the transport produced by itself
+    Response(Response<'a>),
+    // The host cancelled the request that is being processed
+    Cancel(RequestId),
+    // The host resynced the channel of the request being processed with
+    // CTAPHID_INIT. The request is abandoned and gets no reply, this is
+    // the reply to the INIT
+    Abort(RequestId, Response<'a>),
+}
+
+/// The transport state of the CTAPHID interface: open channels, the
+/// message being reassembled and the request being processed
+pub struct CtapHid {
+    assembler: Assembler,
+    channels: Channels,
+    // Channel and id of the request that has been handed out and not
+    // finished yet. Aborted requests are not busy anymore, even if they
+    // have not finished
+    busy: Option<(u32, RequestId)>,
+    // Id of the next request to be handed out
+    next_id: RequestId,
+    // Channel holding CTAPHID_LOCK and when the lock runs out
+    lock: Option<(u32, Instant)>,
+    // Backing storage for the small replies the transport makes itself
+    reply: [u8; INIT_RESPONSE_LEN],
+}
+
+impl Default for CtapHid {
+    fn default() -> Self {
+        CtapHid::new()
+    }
+}
+
+impl CtapHid {
+    pub const fn new() -> Self {
+        CtapHid {
+            assembler: Assembler::new(),
+            channels: Channels::new(),
+            busy: None,
+            next_id: RequestId(0),
+            lock: None,
+            reply: [0; INIT_RESPONSE_LEN],
+        }
+    }
+
+    /// Drops any partially received message and releases the lock,
+    /// but keeps the open channels
+    pub fn reset(&mut self) {
+        self.assembler.reset();
+        self.lock = None;
+    }
+
+    /// Marks a request handed out in an `Event::Request` as answered.
+    /// Finishing a request that was aborted changes nothing
+    pub fn finish(&mut self, id: RequestId) {
+        if self.busy.is_some_and(|(_, busy)| busy == id) {
+            self.busy = None;
+        }
+    }
+
+    /// Marks the request that was just handed out as answered by the
+    /// transport itself, for commands like CTAPHID_WINK that never reach
+    /// the authenticator
+    pub fn answered(&mut self) {
+        self.busy = None;
+    }
+
+    /// Returns when `timeout` needs to be called, if a message is being reassembled
+    pub fn deadline(&self) -> Option<Instant> {
+        self.assembler.deadline()
+    }
+
+    /// Drops a partial message whose continuation packets stopped arriving
+    pub fn timeout(&mut self) -> Option<Event<'_>> {
+        let cid = self.assembler.pending()?;
+        if Instant::now() < self.assembler.deadline {
+            return None;
+        }
+        self.assembler.reset();
+        error(&mut self.reply, cid, ErrorCode::MsgTimeout)
+    }
+
+    pub fn handle_packet(&mut self, packet: &Packet) -> Option<Event<'_>> {
+        let frame = Frame::parse(packet);
+
+        // CTAPHID_INIT always fits in one packet and is handled right away,
+        // so a host can resync no matter what state the channel is in
+        if let Frame::Init {
+            cid,
+            cmd,
+            len,
+            data,
+        } = frame
+        {
+            match Command::try_from(cmd) {
+                Ok(Command::Init) => return self.init(cid, len as usize, data),
+                // Cancelling when nothing is pending is a no-op
+                Ok(Command::Cancel) => {
+                    return match self.busy {
+                        Some((busy, id)) if busy == cid => Some(Event::Cancel(id)),
+                        _ => None,
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        let cid = frame.cid();
+        if !self.channels.touch(cid) {
+            return error(&mut self.reply, cid, ErrorCode::InvalidChannel);
+        }
+        if self.locked_out(cid) || self.busy.is_some() {
+            return error(&mut self.reply, cid, ErrorCode::ChannelBusy);
+        }
+
+        if let Frame::Init { cmd, len, data, .. } = frame {
+            match Command::try_from(cmd) {
+                // CTAPHID_LOCK always fits in one packet and is handled by the transport
+                Ok(Command::Lock) => {
+                    return match self.assembler.pending() {
+                        Some(pending) if pending != cid => {
+                            error(&mut self.reply, cid, ErrorCode::ChannelBusy)
+                        }
+                        _ => self.lock(cid, len as usize, data),
+                    };
+                }
+                // Only the device sends these
+                Ok(Command::Keepalive | Command::Error) | Err(_) => {
+                    return error(&mut self.reply, cid, ErrorCode::InvalidCmd);
+                }
+                _ => {}
+            }
+        }
+
+        let code = match self.assembler.push(frame) {
+            // Pings are echoed straight back without bothering the authenticator
+            Ok(Some(message)) if message.cmd == u8::from(Command::Ping) => {
+                return Some(Event::Response(Response {
+                    cid: message.cid,
+                    cmd: Command::Ping,
+                    data: &message.data,
+                }));
+            }
+            Ok(Some(message)) => {
+                let id = self.next_id;
+                self.next_id = RequestId(id.0.wrapping_add(1));
+                self.busy = Some((message.cid, id));
+                return Some(Event::Request(id, message));
+            }
+            Ok(None) => return None, // Waiting on continuation packets
+            Err(FramingError::InvalidLen) => ErrorCode::InvalidLen,
+            Err(FramingError::InvalidSeq) => ErrorCode::InvalidSeq,
+            Err(FramingError::Busy) => ErrorCode::ChannelBusy,
+            Err(FramingError::UnexpectedCont) => {
+                // Stray continuation packets are ignored, like the spec asks
+                warn!("Dropping unexpected continuation packet on {:#x}", cid);
+                return None;
+            }
+        };
+        error(&mut self.reply, cid, code)
+    }
+
+    fn init(&mut self, cid: u32, len: usize, data: &[u8]) -> Option<Event<'_>> {
+        if len != INIT_NONCE_LEN {
+            return error(&mut self.reply, cid, ErrorCode::InvalidLen);
+        }
+
+        let mut aborted = None;
+        let new_cid = if cid == BROADCAST_CID {
+            let (busy, lock, pending) = (self.busy, self.lock, self.assembler.pending());
+            self.channels.allocate(|cid| {
+                busy.is_some_and(|(busy, _)| busy == cid)
+                    || pending == Some(cid)
+                    || lock.is_some_and(|(holder, _)| holder == cid)
+            })
+        } else if self.channels.touch(cid) {
+            // Resync an existing channel, abandoning whatever it was sending
+            // and the request it is waiting on
+            if self.assembler.pending() == Some(cid) {
+                self.assembler.reset();
+            }
+            if let Some((_, id)) = self.busy.filter(|(busy, _)| *busy == cid) {
+                info!("Channel {:#x} abandoned its request", cid);
+                self.busy = None;
+                aborted = Some(id);
+            }
+            cid
+        } else {
+            return error(&mut self.reply, cid, ErrorCode::InvalidChannel);
+        };
+        info!("Opened ctaphid channel {:#x}", new_cid);
+
+        let response = &mut self.reply;
+        response[..INIT_NONCE_LEN].copy_from_slice(&data[..INIT_NONCE_LEN]);
+        response[8..12].copy_from_slice(&new_cid.to_be_bytes());
+        response[12] = PROTOCOL_VERSION;
+        response[13..16].copy_from_slice(&DEVICE_VERSION);
+        response[16] = CAPABILITIES;
+
+        let response = Response {
+            cid,
+            cmd: Command::Init,
+            data: &self.reply,
+        };
+        Some(match aborted {
+            Some(id) => Event::Abort(id, response),
+            None => Event::Response(response),
+        })
+    }
+
+    fn lock(&mut self, cid: u32, len: usize, data: &[u8]) -> Option<Event<'_>> {
+        if len != 1 {
+            return error(&mut self.reply, cid, ErrorCode::InvalidLen);
+        }
+
+        match data[0] {
+            0 => {
+                info!("Channel {:#x} released the lock", cid);
+                self.lock = None;
+            }
+            seconds @ 1..=MAX_LOCK_SECONDS => {
+                info!("Channel {:#x} locked for {} seconds", cid, seconds);
+                let expires = Instant::now() + Duration::from_secs(seconds as u64);
+                self.lock = Some((cid, expires));
+            }
+            _ => return error(&mut self.reply, cid, ErrorCode::InvalidPar),
+        }
+        reply(&mut self.reply, cid, Command::Lock, &[])
+    }
+
+    /// Returns true when another channel holds an unexpired lock
+    fn locked_out(&mut self, cid: u32) -> bool {
+        match self.lock {
+            Some((_, expires)) if Instant::now() >= expires => {
+                self.lock = None;
+                false
+            }
+            Some((holder, _)) => holder != cid,
+            None => false,
+        }
+    }
+}
+
+// These only borrow the reply buffer, so they can be used while
+// a message borrowed from the assembler is still alive
+fn error(buf: &mut [u8; INIT_RESPONSE_LEN], cid: u32, code: ErrorCode) -> Option<Event<'_>> {
+    warn!("Ctaphid error on channel {:#x}: {}", cid, code);
+    reply(buf, cid, Command::Error, &[code as u8])
+}
+
+fn reply<'a>(
+    buf: &'a mut [u8; INIT_RESPONSE_LEN],
+    cid: u32,
+    cmd: Command,
+    data: &[u8],
+) -> Option<Event<'a>> {
+    let reply = &mut buf[..data.len()];
+    reply.copy_from_slice(data);
+    Some(Event::Response(Response {
+        cid,
+        cmd,
+        data: reply,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    // Not defmt's, which the glob brings in
+    use core::{assert, assert_eq, panic};
+    use embassy_time::MockDriver;
+
+    const NONCE: [u8; INIT_NONCE_LEN] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn packet(cid: u32, cmd: Command, data: &[u8]) -> Packet {
+        Fragments::new(cid, cmd.into(), data).next().unwrap()
+    }
+
+    fn error_code(event: Option<Event<'_>>) -> Option<ErrorCode> {
+        match event {
+            Some(Event::Response(Response {
+                cmd: Command::Error,
+                data: &[code],
+                ..
+            })) => [
+                ErrorCode::InvalidCmd,
+                ErrorCode::InvalidPar,
+                ErrorCode::InvalidLen,
+                ErrorCode::InvalidSeq,
+                ErrorCode::MsgTimeout,
+                ErrorCode::ChannelBusy,
+                ErrorCode::InvalidChannel,
+            ]
+            .into_iter()
+            .find(|known| *known as u8 == code),
+            _ => None,
+        }
+    }
+
+    // Opens a channel the way a host does, with INIT on the broadcast channel
+    fn open(ctaphid: &mut CtapHid) -> u32 {
+        let init = packet(BROADCAST_CID, Command::Init, &NONCE);
+        match ctaphid.handle_packet(&init) {
+            Some(Event::Response(response)) => {
+                assert_eq!(response.data[..INIT_NONCE_LEN], NONCE);
+                u32::from_be_bytes(response.data[8..12].try_into().unwrap())
+            }
+            _ => panic!("INIT was not answered"),
+        }
+    }
+
+    #[test]
+    fn fragments_reassemble() {
+        // Empty, a full init packet, one byte into a continuation packet
+        // and the largest message
+        for (len, packets) in [(0, 1), (57, 1), (58, 2), (MAX_MESSAGE_LEN, 129)] {
+            let data: std::vec::Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let fragments: std::vec::Vec<Packet> =
+                Fragments::new(0x1234, Command::Cbor.into(), &data).collect();
+            assert_eq!(fragments.len(), packets, "{} bytes", len);
+
+            let mut assembler = Assembler::new();
+            let (last, rest) = fragments.split_last().unwrap();
+            for fragment in rest {
+                assert!(assembler.push(Frame::parse(fragment)).unwrap().is_none());
+            }
+            let message = assembler.push(Frame::parse(last)).unwrap().unwrap();
+            assert_eq!(message.cid, 0x1234);
+            assert_eq!(message.cmd, u8::from(Command::Cbor));
+            assert_eq!(message.data[..], data[..]);
+            assert_eq!(assembler.pending(), None);
+        }
+    }
+
+    #[test]
+    fn allocate_evicts_least_recently_used() {
+        let mut channels = Channels::new();
+        let cids: [u32; MAX_CHANNELS] = core::array::from_fn(|_| channels.allocate(|_| false));
+        assert!(channels.touch(cids[0]));
+
+        let new = channels.allocate(|_| false);
+        assert!(!channels.contains(cids[1]));
+        assert!(channels.contains(cids[0]));
+        assert!(channels.contains(new));
+
+        // The next one idle for the longest is in use, so the one after goes
+        channels.allocate(|cid| cid == cids[2]);
+        assert!(channels.contains(cids[2]));
+        assert!(!channels.contains(cids[3]));
+    }
+
+    #[test]
+    fn init_does_not_evict_the_busy_channel() {
+        let mut ctaphid = CtapHid::new();
+        let busy = open(&mut ctaphid);
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(busy, Command::Cbor, &[0x04])),
+            Some(Event::Request(..))
+        ));
+        for _ in 0..MAX_CHANNELS {
+            open(&mut ctaphid);
+        }
+        assert!(ctaphid.channels.contains(busy));
+    }
+
+    #[test]
+    fn init_on_the_busy_channel_aborts() {
+        let mut ctaphid = CtapHid::new();
+        let cid = open(&mut ctaphid);
+        let request = packet(cid, Command::Cbor, &[0x04]);
+        let Some(Event::Request(aborted, _)) = ctaphid.handle_packet(&request) else {
+            panic!("no request");
+        };
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(cid, Command::Init, &NONCE)),
+            Some(Event::Abort(id, _)) if id == aborted
+        ));
+
+        // A new request can start before the aborted one finishes
+        let Some(Event::Request(id, _)) = ctaphid.handle_packet(&request) else {
+            panic!("no request");
+        };
+        assert!(aborted.up_to(id) && !id.up_to(aborted));
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(cid, Command::Cancel, &[])),
+            Some(Event::Cancel(cancelled)) if cancelled == id
+        ));
+        // Finishing the aborted request, even twice, leaves the new one busy
+        ctaphid.finish(aborted);
+        ctaphid.finish(aborted);
+        assert_eq!(
+            error_code(ctaphid.handle_packet(&packet(cid, Command::Ping, &[]))),
+            Some(ErrorCode::ChannelBusy)
+        );
+        ctaphid.finish(id);
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(cid, Command::Ping, &[])),
+            Some(Event::Response(Response {
+                cmd: Command::Ping,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn answered_frees_the_channel() {
+        let mut ctaphid = CtapHid::new();
+        let cid = open(&mut ctaphid);
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(cid, Command::Wink, &[])),
+            Some(Event::Request(..))
+        ));
+        ctaphid.answered();
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(cid, Command::Ping, &[])),
+            Some(Event::Response(Response {
+                cmd: Command::Ping,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn error_codes() {
+        let mut ctaphid = CtapHid::new();
+        let cid = open(&mut ctaphid);
+        let other = open(&mut ctaphid);
+
+        let cases = [
+            (
+                packet(0x5555, Command::Ping, &[]),
+                ErrorCode::InvalidChannel,
+            ),
+            (packet(cid, Command::Keepalive, &[1]), ErrorCode::InvalidCmd),
+            (packet(cid, Command::Init, &[0; 4]), ErrorCode::InvalidLen),
+            (
+                packet(cid, Command::Lock, &[MAX_LOCK_SECONDS + 1]),
+                ErrorCode::InvalidPar,
+            ),
+        ];
+        for (packet, code) in cases {
+            assert_eq!(error_code(ctaphid.handle_packet(&packet)), Some(code));
+        }
+
+        // Longer than any message can be
+        let mut too_long = packet(cid, Command::Cbor, &[]);
+        too_long[5..7].copy_from_slice(&(MAX_MESSAGE_LEN as u16 + 1).to_be_bytes());
+        assert_eq!(
+            error_code(ctaphid.handle_packet(&too_long)),
+            Some(ErrorCode::InvalidLen)
+        );
+
+        // Start a message that needs continuation packets
+        let data = [0; INIT_DATA_LEN + 1];
+        let mut fragments = Fragments::new(cid, Command::Cbor.into(), &data);
+        assert!(ctaphid.handle_packet(&fragments.next().unwrap()).is_none());
+        // Nobody else gets in while it is being reassembled
+        assert_eq!(
+            error_code(ctaphid.handle_packet(&packet(other, Command::Ping, &[]))),
+            Some(ErrorCode::ChannelBusy)
+        );
+        // and its continuation packets have to be in order
+        let mut cont = fragments.next().unwrap();
+        cont[4] = 1;
+        assert_eq!(
+            error_code(ctaphid.handle_packet(&cont)),
+            Some(ErrorCode::InvalidSeq)
+        );
+    }
+
+    // The only test that moves the clock, it is shared by every test
+    #[test]
+    fn lock_expires() {
+        let mut ctaphid = CtapHid::new();
+        let holder = open(&mut ctaphid);
+        let other = open(&mut ctaphid);
+
+        let lock = packet(holder, Command::Lock, &[1]);
+        assert!(matches!(
+            ctaphid.handle_packet(&lock),
+            Some(Event::Response(Response {
+                cmd: Command::Lock,
+                ..
+            }))
+        ));
+        let ping = packet(other, Command::Ping, &[]);
+        assert_eq!(
+            error_code(ctaphid.handle_packet(&ping)),
+            Some(ErrorCode::ChannelBusy)
+        );
+        // The holder itself isn't locked out
+        assert!(matches!(
+            ctaphid.handle_packet(&packet(holder, Command::Ping, &[])),
+            Some(Event::Response(Response {
+                cmd: Command::Ping,
+                ..
+            }))
+        ));
+
+        MockDriver::get().advance(Duration::from_secs(1));
+        assert!(matches!(
+            ctaphid.handle_packet(&ping),
+            Some(Event::Response(Response {
+                cmd: Command::Ping,
+                ..
+            }))
+        ));
+    }
+}
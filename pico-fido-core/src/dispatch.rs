@@ -0,0 +1,163 @@
+// Routes a complete request to the authenticator and collects the reply.
+// Nothing here knows how the bytes got in or out, so ctaphid and the planned
+// ccid interface can share it.
+
+use ctap_types::Vec;
+use defmt::*;
+
+use crate::apdu::{self, Status};
+use crate::ctap::Ctap;
+use crate::platform::Platform;
+
+// Largest request or reply, reported as maxMsgSize in getInfo. It is the
+// most a CTAPHID message can hold, every transport has to carry this much
+pub const MAX_MSG_SIZE: usize = 7609;
+
+pub type Buffer = Vec<u8, MAX_MSG_SIZE>;
+
+/// Why a request couldn't be answered at all. Each transport reports
+/// these its own way, ctaphid as CTAPHID_ERROR codes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Error {
+    // Nothing handles this command
+    InvalidCommand,
+    InvalidParameter,
+    InvalidLength,
+}
+
+/// Handles a vendor specific command (0x40 to 0x7f on ctaphid). The request
+/// is passed in and the reply is written to `response`, which is sent back to
+/// the host under the same command. Returning an error makes the transport
+/// report that instead
+pub type VendorHandler = fn(request: &[u8], response: &mut Buffer) -> Result<(), Error>;
+
+pub struct VendorCommand {
+    pub cmd: u8,
+    pub handler: VendorHandler,
+}
+
+pub enum Request<'a> {
+    // Authenticator API command byte followed by its CBOR parameters
+    Cbor(&'a [u8]),
+    // Ctap1 (U2F) command APDU
+    Apdu(&'a [u8]),
+    Vendor(u8, &'a [u8]),
+}
+
+pub struct Dispatcher<P: Platform> {
+    ctap: Ctap<P>,
+    vendor_commands: &'static [VendorCommand],
+}
+
+impl<P: Platform> Dispatcher<P> {
+    pub const fn new(ctap: Ctap<P>, vendor_commands: &'static [VendorCommand]) -> Self {
+        Dispatcher {
+            ctap,
+            vendor_commands,
+        }
+    }
+
+    /// Runs one request and writes the reply to `response`. Errors from the
+    /// authenticator are part of the reply, only requests that can't be
+    /// answered at all come back as an error for the transport to report
+    pub async fn dispatch(
+        &mut self,
+        request: Request<'_>,
+        response: &mut Buffer,
+    ) -> Result<(), Error> {
+        response.clear();
+        match request {
+            Request::Cbor(request) => self.cbor(request, response).await,
+            Request::Apdu(request) => self.apdu(request, response),
+            Request::Vendor(cmd, request) => {
+                match self.vendor_commands.iter().find(|vendor| vendor.cmd == cmd) {
+                    Some(vendor) => (vendor.handler)(request, response)?,
+                    None => return Err(Error::InvalidCommand),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn cbor(&mut self, request: &[u8], response: &mut Buffer) {
+        let result = match ctap_types::ctap2::Request::deserialize(request) {
+            // Deserializing succeeded, so the command byte is there
+            Ok(parsed) => self.ctap.call(&parsed, &request[1..]).await,
+            Err(err) => {
+                warn!("Ctap2 request could not be deserialized");
+                Err(err.into())
+            }
+        };
+        // Replies are the status byte followed by the cbor encoded response,
+        // if there is one. The buffer fits a whole maxMsgSize message and
+        // its length is exactly what the transport sends
+        match result {
+            Ok(result) => {
+                result.serialize(response);
+                if response.first() != Some(&0) {
+                    warn!("Ctap2 response did not fit in {} bytes", MAX_MSG_SIZE);
+                }
+            }
+            Err(err) => unwrap!(response.push(err as u8)),
+        }
+    }
+
+    fn apdu(&mut self, request: &[u8], response: &mut Buffer) {
+        let status = match apdu::Command::parse(request) {
+            Ok(command) if command.cla != 0 => Status::ClaNotSupported,
+            // U2F is not implemented yet, so there are no instructions to run
+            Ok(command) => {
+                warn!("Unhandled ctap1 instruction: {:#x}", command.ins);
+                Status::InsNotSupported
+            }
+            Err(status) => status,
+        };
+        unwrap!(response.extend_from_slice(&status.to_be_bytes()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Not defmt's, which the glob brings in
+    use core::{assert, assert_eq, assert_ne};
+    use embassy_futures::block_on;
+
+    use crate::cbor::Reader;
+    use crate::host::{AlwaysPresent, Host, MemoryStorage, XorShiftRng};
+    use crate::platform::SystemClock;
+
+    fn dispatch(request: &[u8]) -> Buffer {
+        let ctap = Ctap::<Host<XorShiftRng>>::new(
+            MemoryStorage::default(),
+            XorShiftRng::default(),
+            AlwaysPresent,
+            SystemClock,
+        );
+        let mut dispatcher = Dispatcher::new(block_on(ctap), &[]);
+        let mut response = Buffer::new();
+        block_on(dispatcher.dispatch(Request::Cbor(request), &mut response)).unwrap();
+        response
+    }
+
+    #[test]
+    fn replies_are_the_status_then_cbor() {
+        // getInfo, a map that ends exactly where the reply does
+        let response = dispatch(&[0x04]);
+        let (&status, cbor) = response.split_first().unwrap();
+        assert_eq!(status, 0);
+        assert!(Reader::new(cbor).map().is_ok());
+        assert!(Reader::new(cbor).skip().is_ok());
+        assert!(Reader::new(&cbor[..cbor.len() - 1]).skip().is_err());
+    }
+
+    #[test]
+    fn errors_are_just_the_status() {
+        // A command that doesn't exist and a getAssertion without parameters
+        for request in [&[0x99][..], &[0x02]] {
+            let response = dispatch(request);
+            assert_eq!(response.len(), 1);
+            assert_ne!(response[0], 0);
+        }
+    }
+}
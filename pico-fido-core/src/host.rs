@@ -0,0 +1,124 @@
+// A platform for running the authenticator on a host, shared by the
+// simulator, the fuzz targets and the tests. Storage lasts as long as the
+// process and the user is always present.
+
+extern crate std;
+
+use core::marker::PhantomData;
+use embassy_time::{Duration, Instant, Timer};
+use rand_core::{impls, CryptoRng, RngCore};
+use std::vec::Vec;
+
+use crate::platform::{Clock, Platform, Storage, StorageError, SystemClock, UserPresence};
+
+/// Runs on the host with `R` as the source of randomness and `C` telling the time
+pub struct Host<R, C = SystemClock>(PhantomData<(R, C)>);
+
+impl<R: RngCore + CryptoRng, C: Clock> Platform for Host<R, C> {
+    type Storage = MemoryStorage;
+    type Rng = R;
+    type UserPresence = AlwaysPresent;
+    type Clock = C;
+}
+
+// Replacing the saved vec in one go makes saves atomic
+#[derive(Default)]
+pub struct MemoryStorage(Vec<u8>);
+
+impl Storage for MemoryStorage {
+    async fn load(&mut self, buf: &mut [u8]) -> Result<usize, StorageError> {
+        let saved = buf.get_mut(..self.0.len()).ok_or(StorageError)?;
+        saved.copy_from_slice(&self.0);
+        Ok(saved.len())
+    }
+
+    async fn save(&mut self, data: &[u8]) -> Result<(), StorageError> {
+        self.0 = data.to_vec();
+        Ok(())
+    }
+}
+
+// There is no button to press on the host
+pub struct AlwaysPresent;
+
+impl UserPresence for AlwaysPresent {
+    async fn confirm(&mut self) {}
+}
+
+/// Always reads as boot time, so authenticatorReset, which is only allowed
+/// right after power up, keeps working. Delays still take real time
+pub struct BootClock;
+
+impl Clock for BootClock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks(0)
+    }
+
+    async fn delay(&mut self, duration: Duration) {
+        Timer::after(duration).await
+    }
+}
+
+/// xorshift64, deterministic so runs can be reproduced. Never use this
+/// for real keys
+pub struct XorShiftRng(u64);
+
+impl XorShiftRng {
+    /// `seed` can be anything but 0, which only ever gives 0
+    pub const fn new(seed: u64) -> Self {
+        XorShiftRng(seed)
+    }
+}
+
+impl Default for XorShiftRng {
+    fn default() -> Self {
+        XorShiftRng::new(0x853c_49e6_748f_ea9b)
+    }
+}
+
+impl RngCore for XorShiftRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for XorShiftRng {}
+
+// A defmt logger that drops everything, defmt output needs a probe to
+// decode, and defmt panics sent to the regular panic. Only one of each can
+// be linked in, so binaries that bring their own leave the feature off
+#[cfg(any(test, feature = "host-logger"))]
+mod logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        core::panic!("defmt panic")
+    }
+}
@@ -0,0 +1,256 @@
+use cbor_smol::{cbor_deserialize, cbor_serialize};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use ctap_types::{Bytes, Vec};
+use defmt::*;
+use rand_core::CryptoRngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::auth_data::CREDENTIAL_ID_LEN;
+use crate::platform::{Storage, StorageError};
+
+// Largest encoded Keys that can be loaded or saved
+const KEYS_BUF: usize = 2048;
+
+// How many credentials fit in storage
+pub const MAX_CREDENTIALS: usize = 10;
+
+// Random credential ids of stored credentials
+pub const STORED_ID_LEN: usize = 16;
+
+// Credentials that aren't discoverable aren't stored. Their id carries the
+// credential sealed under the master key instead:
+//   version (1) | nonce (12) | alg (2) rpIdHash (32) secret (32) | tag (16)
+// with the version as associated data
+const WRAPPED_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const SEALED_LEN: usize = 2 + 32 + 32;
+const TAG_LEN: usize = 16;
+pub const WRAPPED_ID_LEN: usize = 1 + NONCE_LEN + SEALED_LEN + TAG_LEN;
+
+// The longest credential id this device hands out
+pub const MAX_ID_LEN: usize = if WRAPPED_ID_LEN > STORED_ID_LEN {
+    WRAPPED_ID_LEN
+} else {
+    STORED_ID_LEN
+};
+const _: () = core::assert!(MAX_ID_LEN <= CREDENTIAL_ID_LEN);
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CtapCredential {
+    pub id: Bytes<CREDENTIAL_ID_LEN>,
+    pub rp_id_hash: Bytes<32>,
+    pub user_id: Bytes<64>,
+    // COSE algorithm identifier
+    pub alg: i32,
+    pub secret: Bytes<32>,
+}
+
+// Saved to storage as cbor
+#[derive(Deserialize, Serialize)]
+pub struct Keys {
+    // Wraps the credentials that aren't stored. Replaced on reset, which
+    // leaves every id wrapped before that unusable
+    master_key: Bytes<32>,
+    // Counts every signature made by any credential
+    pub sign_count: u32,
+    pub credentials: Vec<CtapCredential, MAX_CREDENTIALS>,
+}
+
+impl Keys {
+    /// A fresh set of keys with a new master key and no credentials
+    pub fn new(rng: &mut impl CryptoRngCore) -> Self {
+        let mut master_key = [0; 32];
+        rng.fill_bytes(&mut master_key);
+        Keys {
+            master_key: Bytes::from_slice(&master_key).unwrap(),
+            sign_count: 0,
+            credentials: Vec::new(),
+        }
+    }
+
+    /// Reads the keys from storage, starting fresh only if nothing was ever
+    /// saved. Keys that can't be read are an error rather than replaced,
+    /// a new master key would make every id handed out so far unusable
+    pub async fn load(
+        storage: &mut impl Storage,
+        rng: &mut impl CryptoRngCore,
+    ) -> Result<Self, StorageError> {
+        let mut buf = [0; KEYS_BUF];
+        match storage.load(&mut buf).await? {
+            0 => {
+                info!("No saved keys, starting fresh");
+                Ok(Keys::new(rng))
+            }
+            len => cbor_deserialize(&buf[..len]).map_err(|_| {
+                error!("Saved keys could not be decoded");
+                StorageError
+            }),
+        }
+    }
+
+    pub async fn save(&self, storage: &mut impl Storage) -> Result<(), StorageError> {
+        let mut buf = [0; KEYS_BUF];
+        let data = cbor_serialize(self, &mut buf).map_err(|_| StorageError)?;
+        storage.save(data).await
+    }
+
+    pub fn find(&self, id: &[u8]) -> Option<&CtapCredential> {
+        self.credentials
+            .iter()
+            .find(|credential| &credential.id[..] == id)
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.master_key))
+    }
+
+    /// Seals a credential into an id that can be handed out
+    /// instead of storing the credential
+    pub fn seal(
+        &self,
+        rng: &mut impl CryptoRngCore,
+        rp_id_hash: &[u8; 32],
+        alg: i32,
+        secret: &[u8; 32],
+    ) -> Bytes<CREDENTIAL_ID_LEN> {
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let mut sealed = [0; SEALED_LEN];
+        // Every supported COSE identifier fits
+        sealed[..2].copy_from_slice(&(alg as i16).to_be_bytes());
+        sealed[2..34].copy_from_slice(rp_id_hash);
+        sealed[34..].copy_from_slice(secret);
+        // Only fails for messages far longer than this
+        let tag = unwrap!(self
+            .cipher()
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[WRAPPED_VERSION], &mut sealed)
+            .ok());
+
+        let mut id = Bytes::new();
+        for part in [&[WRAPPED_VERSION][..], &nonce[..], &sealed[..], &tag[..]] {
+            unwrap!(id.extend_from_slice(part).ok());
+        }
+        id
+    }
+
+    /// Opens an id made by `seal` for the relying party it was made for.
+    /// Ids from other authenticators, from before a reset or for other
+    /// relying parties give None
+    pub fn open(&self, id: &[u8], rp_id_hash: &[u8; 32]) -> Option<CtapCredential> {
+        if id.len() != WRAPPED_ID_LEN || id[0] != WRAPPED_VERSION {
+            return None;
+        }
+        let (nonce, rest) = id[1..].split_at(NONCE_LEN);
+        let (sealed, tag) = rest.split_at(SEALED_LEN);
+        let mut opened = [0; SEALED_LEN];
+        opened.copy_from_slice(sealed);
+        self.cipher()
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &[WRAPPED_VERSION],
+                &mut opened,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+        if opened[2..34] != rp_id_hash[..] {
+            return None;
+        }
+        Some(CtapCredential {
+            id: Bytes::from_slice(id).ok()?,
+            rp_id_hash: Bytes::from_slice(rp_id_hash).ok()?,
+            // Not discoverable, so there is no user to return
+            user_id: Bytes::new(),
+            alg: i16::from_be_bytes([opened[0], opened[1]]).into(),
+            secret: Bytes::from_slice(&opened[34..]).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Not defmt's, which the glob brings in
+    use core::{assert, assert_eq};
+    use embassy_futures::block_on;
+
+    use crate::host::{MemoryStorage, XorShiftRng};
+
+    const RP_ID_HASH: [u8; 32] = [0x11; 32];
+    const SECRET: [u8; 32] = [0x22; 32];
+    // ES256
+    const ALG: i32 = -7;
+
+    fn sealed(keys: &Keys, rng: &mut XorShiftRng) -> Bytes<CREDENTIAL_ID_LEN> {
+        keys.seal(rng, &RP_ID_HASH, ALG, &SECRET)
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let mut rng = XorShiftRng::default();
+        let keys = Keys::new(&mut rng);
+        let id = sealed(&keys, &mut rng);
+        assert_eq!(id.len(), WRAPPED_ID_LEN);
+
+        let credential = keys.open(&id, &RP_ID_HASH).unwrap();
+        assert_eq!(credential.id, id);
+        assert_eq!(credential.rp_id_hash[..], RP_ID_HASH);
+        assert_eq!(credential.alg, ALG);
+        assert_eq!(credential.secret[..], SECRET);
+        assert!(credential.user_id.is_empty());
+    }
+
+    #[test]
+    fn open_rejects_other_relying_parties() {
+        let mut rng = XorShiftRng::default();
+        let keys = Keys::new(&mut rng);
+        let id = sealed(&keys, &mut rng);
+        assert!(keys.open(&id, &[0x33; 32]).is_none());
+    }
+
+    #[test]
+    fn open_rejects_ids_from_before_a_reset() {
+        let mut rng = XorShiftRng::default();
+        let keys = Keys::new(&mut rng);
+        let id = sealed(&keys, &mut rng);
+        let reset = Keys::new(&mut rng);
+        assert!(reset.open(&id, &RP_ID_HASH).is_none());
+    }
+
+    #[test]
+    fn open_rejects_damaged_ids() {
+        let mut rng = XorShiftRng::default();
+        let keys = Keys::new(&mut rng);
+        let id = sealed(&keys, &mut rng);
+
+        assert!(keys.open(&id[..id.len() - 1], &RP_ID_HASH).is_none());
+
+        let mut version = id.clone();
+        version[0] = WRAPPED_VERSION + 1;
+        assert!(keys.open(&version, &RP_ID_HASH).is_none());
+
+        let mut tag = id.clone();
+        tag[WRAPPED_ID_LEN - 1] ^= 0x01;
+        assert!(keys.open(&tag, &RP_ID_HASH).is_none());
+    }
+
+    #[test]
+    fn load_keeps_the_master_key() {
+        let mut rng = XorShiftRng::default();
+        let mut storage = MemoryStorage::default();
+        let keys = block_on(Keys::load(&mut storage, &mut rng)).unwrap();
+        block_on(keys.save(&mut storage)).unwrap();
+        let id = sealed(&keys, &mut rng);
+
+        let loaded = block_on(Keys::load(&mut storage, &mut rng)).unwrap();
+        assert!(loaded.open(&id, &RP_ID_HASH).is_some());
+    }
+
+    #[test]
+    fn load_refuses_keys_it_cannot_decode() {
+        let mut rng = XorShiftRng::default();
+        let mut storage = MemoryStorage::default();
+        block_on(storage.save(&[0xff, 0x00])).unwrap();
+        assert!(block_on(Keys::load(&mut storage, &mut rng)).is_err());
+    }
+}
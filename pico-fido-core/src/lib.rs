@@ -0,0 +1,17 @@
+//! Platform independent parts of the authenticator: CTAP command handling
+//! and the framing used by its transports. The firmware and the host
+//! simulator are both built on top of this.
+
+#![no_std]
+
+pub mod apdu;
+pub mod auth_data;
+pub mod cbor;
+pub mod credential;
+pub mod ctap;
+pub mod ctaphid;
+pub mod dispatch;
+#[cfg(any(test, feature = "std"))]
+pub mod host;
+pub mod keys;
+pub mod platform;
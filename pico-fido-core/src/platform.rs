@@ -0,0 +1,57 @@
+// What the authenticator needs from the device it runs on. The firmware
+// implements these for the pico, the simulator for the host.
+
+use core::future::Future;
+use defmt::Format;
+use embassy_time::{Duration, Instant, Timer};
+use rand_core::{CryptoRng, RngCore};
+
+pub trait Platform {
+    type Storage: Storage;
+    type Rng: RngCore + CryptoRng;
+    type UserPresence: UserPresence;
+    type Clock: Clock;
+}
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct StorageError;
+
+/// Somewhere to keep the authenticator state across power cycles
+pub trait Storage {
+    /// Reads what was last saved into `buf` and returns its length,
+    /// 0 if nothing has been saved yet. Saved data that can't be read back
+    /// is an error, not an empty storage
+    fn load(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, StorageError>>;
+
+    /// Replaces whatever was saved before with `data`. This has to be
+    /// atomic: if power is lost while saving, the next load returns either
+    /// the old data or the new
+    fn save(&mut self, data: &[u8]) -> impl Future<Output = Result<(), StorageError>>;
+}
+
+/// How the device asks the user to confirm they are there,
+/// a button press on the pico
+pub trait UserPresence {
+    /// Resolves once the user has confirmed. The future is dropped
+    /// if the request is cancelled or times out
+    fn confirm(&mut self) -> impl Future<Output = ()>;
+}
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    fn delay(&mut self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+/// Clock backed by the embassy time driver
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn delay(&mut self, duration: Duration) {
+        Timer::after(duration).await
+    }
+}
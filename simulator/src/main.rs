@@ -0,0 +1,102 @@
+// Runs the authenticator on the host so it can be tested without a pico.
+// Raw 64 byte CTAPHID packets are exchanged over UDP, one packet per
+// datagram, like the Solo and OpenSK simulators. Replies go to whoever sent
+// the last packet.
+//
+//   cargo run -- [address to listen on, 127.0.0.1:8111 by default]
+//
+// Nothing is persisted and the user is always present, so every run starts
+// from a freshly reset authenticator that confirms everything straight away.
+// It never gets older than just plugged in either, so it can be reset at any
+// time instead of only in the first seconds.
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+use embassy_futures::block_on;
+use embassy_time::Instant;
+use pico_fido_core::ctap::Ctap;
+use pico_fido_core::ctaphid::{self, Command, CtapHid, Event, Message, PACKET_SIZE};
+use pico_fido_core::dispatch::Dispatcher;
+use pico_fido_core::host::{AlwaysPresent, BootClock, Host, MemoryStorage};
+use rand::rngs::OsRng;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8111";
+
+type Simulator = Host<OsRng, BootClock>;
+
+fn main() -> std::io::Result<()> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.into());
+    let socket = UdpSocket::bind(&address)?;
+    println!("Listening for ctaphid packets on {}", address);
+
+    let mut ctaphid = CtapHid::new();
+    let ctap = Ctap::<Simulator>::new(MemoryStorage::default(), OsRng, AlwaysPresent, BootClock);
+    let mut dispatcher = Dispatcher::new(block_on(ctap), &[]);
+    let mut packet = [0; PACKET_SIZE];
+    let mut host: Option<SocketAddr> = None;
+
+    loop {
+        // Wake up in time to drop a message the host stopped sending
+        let timeout = ctaphid.deadline().map(|deadline| {
+            let left = deadline.saturating_duration_since(Instant::now());
+            std::time::Duration::from_micros(left.as_micros().max(1))
+        });
+        socket.set_read_timeout(timeout)?;
+
+        let response = match socket.recv_from(&mut packet) {
+            Ok((PACKET_SIZE, from)) => {
+                host = Some(from);
+                match ctaphid.handle_packet(&packet) {
+                    Some(Event::Response(response) | Event::Abort(_, response)) => {
+                        response.to_message()
+                    }
+                    Some(Event::Request(id, request)) => {
+                        let response = run(&mut dispatcher, request);
+                        ctaphid.finish(id);
+                        response
+                    }
+                    // Requests finish before the next packet is read,
+                    // so there is never anything to cancel or abort
+                    Some(Event::Cancel(_)) | None => continue,
+                }
+            }
+            Ok((len, _)) => {
+                eprintln!("Ignoring short ctaphid packet of {} bytes", len);
+                continue;
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                match ctaphid.timeout() {
+                    Some(Event::Response(response)) => response.to_message(),
+                    _ => continue,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(host) = host {
+            for packet in response.packets() {
+                socket.send_to(&packet, host)?;
+            }
+        }
+    }
+}
+
+fn run(dispatcher: &mut Dispatcher<Simulator>, request: &Message) -> Message {
+    if request.cmd == u8::from(Command::Wink) {
+        println!("Wink");
+        return Message::new(request.cid, Command::Wink);
+    }
+
+    // The same path the firmware's authenticator task takes
+    let response = block_on(ctaphid::dispatch(dispatcher, request));
+    if response.cmd == u8::from(Command::Error) {
+        eprintln!(
+            "Ctaphid command {:#x} failed: {:?}",
+            request.cmd, response.data
+        );
+    }
+    response
+}
@@ -6,10 +6,10 @@
 static HEAP: Heap = Heap::empty();
 extern crate alloc;
 
-use core::cell::RefCell;
 use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::*;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
 use embassy_rp::flash::Async;
 use embassy_rp::flash::Flash;
 use embassy_rp::gpio::{Level, Output};
@@ -17,26 +17,33 @@ use embassy_rp::peripherals::BOOTSEL;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::channel::Channel;
-use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_alloc::Heap;
 use static_cell::StaticCell;
 use usbd_hid::descriptor::KeyboardUsage;
 use {defmt_rtt as _, panic_probe as _};
 
 mod usb;
-use usb::{create_usb_tasks, CTAP_CHANNEL_LEN, HID_CHANNEL_LEN};
-mod ctap;
-use ctap::Ctap;
-mod keys;
-use keys::Keys;
+use pico_fido_core::ctap::Ctap;
+use pico_fido_core::dispatch::{Dispatcher, VendorCommand};
+use pico_fido_core::platform::{SystemClock, UserPresence};
+use usb::{create_usb_tasks, ctap_authenticator};
+use usb::{CTAP_CHANNEL_LEN, CTAP_REQUEST_LEN, HID_CHANNEL_LEN};
+mod platform;
+use platform::{CryptRng, FlashStorage, Pico};
 
 // Flash config from memory.x
 const ADDR_OFFSET: u32 = 0x100000;
 const FLASH_SIZE: usize = 2 * 1024 * 1024;
 
-type CtapMessage = [u8; 64];
+type CtapMessage = pico_fido_core::ctaphid::Message;
+// A message for the authenticator and the id the transport gave it
+type CtapRequest = (pico_fido_core::ctaphid::RequestId, CtapMessage);
+type Authenticator = Dispatcher<Pico>;
+
+// Device specific CTAPHID commands, register new tooling commands here
+static VENDOR_COMMANDS: &[VendorCommand] = &[];
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -44,7 +51,15 @@ async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
     let flash = Flash::<_, Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
-    let keys: RefCell<Mutex<NoopRawMutex, Keys>> = RefCell::new(Mutex::new(Keys::new(flash)));
+    // Saved keys that can't be read are kept until the authenticator is
+    // reset, which still works
+    let ctap = Ctap::<Pico>::new(
+        FlashStorage::new(flash),
+        CryptRng::new(),
+        BootselButton,
+        SystemClock,
+    )
+    .await;
 
     // Get board specific pin
     let led_pin = {
@@ -56,16 +71,20 @@ async fn main(spawner: Spawner) {
         StaticCell::new();
     static CTAP_CHANNEL: StaticCell<Channel<NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>> =
         StaticCell::new();
+    static CTAP_REQUEST_CHANNEL: StaticCell<Channel<NoopRawMutex, CtapRequest, CTAP_REQUEST_LEN>> =
+        StaticCell::new();
 
     let keyboard_ch =
         HID_KEYBOARD_CHANNEL.init(Channel::<NoopRawMutex, KeyboardUsage, HID_CHANNEL_LEN>::new());
     let ctap_ch = CTAP_CHANNEL.init(Channel::<NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>::new());
+    let request_ch =
+        CTAP_REQUEST_CHANNEL.init(Channel::<NoopRawMutex, CtapRequest, CTAP_REQUEST_LEN>::new());
     let (usb_task, hid_writer, hid_reader, ctap_reader, ctap_writer) = create_usb_tasks(
         p.USB,
-        keys,
         keyboard_ch.receiver(),
         ctap_ch.sender(),
         ctap_ch.receiver(),
+        request_ch.sender(),
     );
 
     spawner.spawn(blinker(led_pin)).unwrap();
@@ -75,9 +94,16 @@ async fn main(spawner: Spawner) {
     spawner.spawn(hid_reader).unwrap();
     spawner.spawn(ctap_writer).unwrap();
     spawner.spawn(ctap_reader).unwrap();
+    spawner
+        .spawn(ctap_authenticator(
+            Dispatcher::new(ctap, VENDOR_COMMANDS),
+            request_ch.receiver(),
+            ctap_ch.sender(),
+        ))
+        .unwrap();
 }
 
-pub const BOOTSEL_BUTTON: AtomicBool = AtomicBool::new(false);
+pub static BOOTSEL_BUTTON: AtomicBool = AtomicBool::new(false);
 
 // This sucks because it has to pull the button status
 // but without forcing people to bring their own button
@@ -91,37 +117,90 @@ pub async fn bootsel_pressed(mut button: BOOTSEL) {
     }
 }
 
+// User presence is confirmed by pressing the bootsel button
+pub struct BootselButton;
+
+impl UserPresence for BootselButton {
+    async fn confirm(&mut self) {
+        LED_SIGNAL.signal(LedState::Confirm);
+        while !BOOTSEL_BUTTON.load(Ordering::Relaxed) {
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        LED_SIGNAL.signal(LedState::Processing);
+    }
+}
+
 pub static LED_SIGNAL: Signal<CriticalSectionRawMutex, LedState> = Signal::new();
 
-#[derive(Debug, Default, Format)]
+#[derive(Clone, Copy, Debug, Default, Format)]
 pub enum LedState {
     Confirm, // Waiting for user to confirm
     #[default]
     Idle, // Pico goes to sleep
     Active,  // Awake and waiting for a command
     Processing, // Busy and cannot receive new commands
+    Wink,    // Host asked to identify this device
 }
 
+// How long a wink lasts before the led goes back to what it was doing
+const WINK_DURATION: Duration = Duration::from_secs(3);
+
 #[embassy_executor::task]
 pub async fn blinker(mut led: Output<'static>) {
     let mut signal = LedState::default();
+    // When the current wink ends and the state to go back to
+    let mut wink: Option<(Instant, LedState)> = None;
 
     loop {
-        if let Some(new_signal) = LED_SIGNAL.try_take() {
-            info!("Got new signal: {}", new_signal);
-            signal = new_signal;
-        }
-
         let (on_time, off_time) = match signal {
             LedState::Confirm => (Duration::from_secs(1), Duration::from_millis(100)),
             LedState::Idle => (Duration::from_millis(500), Duration::from_secs(1)),
             LedState::Active => (Duration::from_millis(200), Duration::from_millis(200)),
             LedState::Processing => (Duration::from_millis(50), Duration::from_millis(50)),
+            LedState::Wink => (Duration::from_millis(100), Duration::from_millis(400)),
         };
 
-        led.set_high();
-        Timer::after(on_time).await;
-        led.set_low();
-        Timer::after(off_time).await;
+        let blink = async {
+            loop {
+                led.set_high();
+                Timer::after(on_time).await;
+                led.set_low();
+                Timer::after(off_time).await;
+            }
+        };
+        let wink_end = async {
+            match wink {
+                Some((end, _)) => Timer::at(end).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        // Start the new pattern as soon as the state changes, not after the
+        // current blink
+        match select3(LED_SIGNAL.wait(), blink, wink_end).await {
+            Either3::First(new_signal) => {
+                info!("Got new signal: {}", new_signal);
+                match (new_signal, wink) {
+                    (LedState::Wink, _) => {
+                        let previous = wink.map_or(signal, |(_, previous)| previous);
+                        wink = Some((Instant::now() + WINK_DURATION, previous));
+                        signal = LedState::Wink;
+                    }
+                    // Asking the user to confirm takes over the led, anything
+                    // else is what the led goes back to after the wink
+                    (LedState::Confirm, _) | (_, None) => {
+                        wink = None;
+                        signal = new_signal;
+                    }
+                    (_, Some((end, _))) => wink = Some((end, new_signal)),
+                }
+            }
+            Either3::Second(_) => {}
+            Either3::Third(()) => {
+                if let Some((_, previous)) = wink.take() {
+                    signal = previous;
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,216 @@
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::flash::Async;
+use embassy_rp::flash::Flash;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_rp::peripherals::FLASH;
+
+use defmt::*;
+use pico_fido_core::platform::{Platform, Storage, StorageError, SystemClock};
+use rand::{CryptoRng, Rng, RngCore};
+
+use super::{BootselButton, ADDR_OFFSET, FLASH_SIZE};
+
+pub struct Pico;
+
+impl Platform for Pico {
+    type Storage = FlashStorage;
+    type Rng = CryptRng;
+    type UserPresence = BootselButton;
+    type Clock = SystemClock;
+}
+
+// THIS IS A VERY VERY BAD SOURCE OF RANDOMNESS
+// but will work for now
+pub struct CryptRng(u32);
+
+impl CryptRng {
+    pub fn new() -> Self {
+        let mut rng = RoscRng;
+        CryptRng(rng.gen())
+    }
+}
+
+impl RngCore for CryptRng {
+    fn next_u32(&mut self) -> u32 {
+        RoscRng::next_u32(&mut RoscRng)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RoscRng::next_u64(&mut RoscRng)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RoscRng::fill_bytes(&mut RoscRng, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RoscRng::try_fill_bytes(&mut RoscRng, dest)
+    }
+}
+
+impl CryptoRng for CryptRng {}
+
+// The saved state alternates between two sectors from ADDR_OFFSET, so a save
+// cut short by a power loss leaves the previous copy intact. Each copy is
+//   format (4) | sequence (4) | length (4) | crc32 (4) | data
+// little endian, with the crc over the sequence, length and data. The header
+// is written after the data, and the valid copy with the highest sequence is
+// the current one. A sector that doesn't start with FORMAT, like erased flash
+// or whatever was there before this firmware, has nothing saved in it
+const SECTORS: [u32; 2] = [ADDR_OFFSET, ADDR_OFFSET + ERASE_SIZE as u32];
+const FORMAT: [u8; 4] = *b"PFK1";
+const HEADER_SIZE: usize = 16;
+const STORAGE_SIZE: usize = ERASE_SIZE - HEADER_SIZE;
+
+// What a sector holds
+enum Saved {
+    Erased,
+    Corrupt,
+    Valid { sequence: u32, len: usize },
+}
+
+pub struct FlashStorage {
+    flash: Flash<'static, FLASH, Async, FLASH_SIZE>,
+    // Sector and sequence of the current copy, once known
+    current: Option<(usize, u32)>,
+}
+
+impl FlashStorage {
+    pub fn new(flash: Flash<'static, FLASH, Async, FLASH_SIZE>) -> Self {
+        FlashStorage {
+            flash,
+            current: None,
+        }
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        self.flash
+            .blocking_read(offset, buf)
+            .map_err(|e| warn!("Failed to read flash: {}", e))
+            .map_err(|_| StorageError)
+    }
+
+    // Reads a sector's header and checks its data against the crc
+    fn check(&mut self, sector: usize) -> Result<Saved, StorageError> {
+        let mut header = [0; HEADER_SIZE];
+        self.read(SECTORS[sector], &mut header)?;
+        if header[0..4] != FORMAT {
+            return Ok(Saved::Erased);
+        }
+
+        let sequence = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let expected = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if len > STORAGE_SIZE {
+            return Ok(Saved::Corrupt);
+        }
+
+        let mut crc = crc32(CRC_INIT, &header[4..12]);
+        let mut chunk = [0; 256];
+        let mut offset = 0;
+        while offset < len {
+            let chunk = &mut chunk[..(len - offset).min(256)];
+            self.read(SECTORS[sector] + (HEADER_SIZE + offset) as u32, chunk)?;
+            crc = crc32(crc, chunk);
+            offset += chunk.len();
+        }
+        Ok(match !crc == expected {
+            true => Saved::Valid { sequence, len },
+            false => Saved::Corrupt,
+        })
+    }
+
+    fn copies(&mut self) -> Result<[Saved; 2], StorageError> {
+        Ok([self.check(0)?, self.check(1)?])
+    }
+}
+
+// Sector, sequence and length of the newest valid copy
+fn newest(copies: &[Saved; 2]) -> Option<(usize, u32, usize)> {
+    match *copies {
+        [Saved::Valid { sequence: a, len }, Saved::Valid { sequence: b, .. }]
+            if a.wrapping_sub(b) as i32 > 0 =>
+        {
+            Some((0, a, len))
+        }
+        [_, Saved::Valid { sequence, len }] => Some((1, sequence, len)),
+        [Saved::Valid { sequence, len }, _] => Some((0, sequence, len)),
+        _ => None,
+    }
+}
+
+impl Storage for FlashStorage {
+    async fn load(&mut self, buf: &mut [u8]) -> Result<usize, StorageError> {
+        let copies = self.copies()?;
+        let Some((sector, sequence, len)) = newest(&copies) else {
+            return match copies {
+                // The first save goes to the first sector, so this is
+                // one that was cut short and nothing was saved
+                [Saved::Erased | Saved::Corrupt, Saved::Erased] => Ok(0),
+                _ => {
+                    error!("Saved state is corrupt");
+                    Err(StorageError)
+                }
+            };
+        };
+        self.current = Some((sector, sequence));
+        if len > buf.len() {
+            warn!("Saved state of {} bytes does not fit", len);
+            return Err(StorageError);
+        }
+        self.read(SECTORS[sector] + HEADER_SIZE as u32, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    async fn save(&mut self, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > STORAGE_SIZE {
+            warn!("State of {} bytes does not fit in flash", data.len());
+            return Err(StorageError);
+        }
+
+        // Without a valid copy there is nothing to keep, corrupt ones are
+        // overwritten so a reset can replace them
+        let current = match self.current {
+            Some(current) => Some(current),
+            None => newest(&self.copies()?).map(|(sector, sequence, _)| (sector, sequence)),
+        };
+        // Overwrite the older copy, never the current one
+        let (sector, sequence) = match current {
+            Some((sector, sequence)) => (1 - sector, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        let mut header = [0; HEADER_SIZE];
+        header[0..4].copy_from_slice(&FORMAT);
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        let crc = !crc32(crc32(CRC_INIT, &header[4..12]), data);
+        header[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        let start = SECTORS[sector];
+        let result = self
+            .flash
+            .blocking_erase(start, start + ERASE_SIZE as u32)
+            .and_then(|_| self.flash.blocking_write(start + HEADER_SIZE as u32, data))
+            .and_then(|_| self.flash.blocking_write(start, &header));
+        result
+            .map_err(|e| warn!("Failed to write flash: {}", e))
+            .map_err(|_| StorageError)?;
+        self.current = Some((sector, sequence));
+        Ok(())
+    }
+}
+
+const CRC_INIT: u32 = 0xffff_ffff;
+
+// CRC-32 (IEEE), bit by bit since it only runs over a few kilobytes.
+// Start from CRC_INIT and invert the result
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & 0u32.wrapping_sub(crc & 1));
+        }
+    }
+    crc
+}
@@ -1,49 +1,114 @@
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::channel::{Receiver, Sender};
-use embassy_usb::class::hid::{HidReader, HidWriter, ReadError, ReportId, RequestHandler};
-use embassy_usb::control::OutResponse;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker, Timer};
+use embassy_usb::class::hid::{HidReader, HidWriter, ReadError};
+use embassy_usb::driver::EndpointError;
 
-use alloc::vec::Vec;
-use ctap_types::Rpc;
+use core::cell::Cell;
+use core::future;
+use core::sync::atomic::Ordering;
 use defmt::*;
+use pico_fido_core::ctap::{CANCEL_SIGNAL, USER_PRESENCE_PENDING};
+use pico_fido_core::ctaphid::{
+    self, Command, CtapHid, ErrorCode, Event, KeepaliveStatus, Packet, Payload, RequestId,
+    PACKET_SIZE,
+};
 use usbd_hid::descriptor::CtapReport;
 
-use super::{Ctap, CtapMessage};
+use super::{CtapMessage, CtapRequest};
+use crate::{Authenticator, LedState, LED_SIGNAL};
 
-pub const CTAP_CHANNEL_LEN: usize = 10;
+// Every message can be up to MAX_MESSAGE_LEN bytes, so keep this short
+pub const CTAP_CHANNEL_LEN: usize = 2;
+// Only one request is processed at a time, the transport answers
+// ChannelBusy to anything else until it's done
+pub const CTAP_REQUEST_LEN: usize = 1;
 
-pub const CTAP_WRITER_BUF: usize = 8;
-pub const CTAP_READER_BUF: usize = 7609;
+pub const CTAP_WRITER_BUF: usize = PACKET_SIZE;
 
-async fn handle_response(
-    ctap: &mut Ctap,
-    sender: &mut Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
-    buf: &[u8],
+// Set by the usb handler when the bus resets
+pub static CTAPHID_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Set by the authenticator once the response to a request has been queued.
+// Requests finish in order and only the newest one can still be busy, so
+// when several finish before the reader gets to it the last id is all it needs
+static REQUEST_DONE: Signal<CriticalSectionRawMutex, RequestId> = Signal::new();
+
+// Hosts give up on a request if they don't hear from the device for a while
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+// The last request the host abandoned by resyncing its channel, its response
+// is dropped instead of sent. Only the newest request can be abandoned, so
+// every request up to it was either abandoned too or has finished
+static ABANDONED: Mutex<CriticalSectionRawMutex, Cell<Option<RequestId>>> =
+    Mutex::new(Cell::new(None));
+
+// The last request the host cancelled, it may still be waiting in the queue
+static CANCELLED: Mutex<CriticalSectionRawMutex, Cell<Option<RequestId>>> =
+    Mutex::new(Cell::new(None));
+
+// Channel of the request the authenticator is working on
+static KEEPALIVE: Mutex<CriticalSectionRawMutex, Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Runs complete requests from the transport, one at a time. This lives in
+/// its own task so the reader keeps servicing the host (CANCEL, INIT, other
+/// channels) while the authenticator waits on the user
+#[embassy_executor::task]
+pub async fn ctap_authenticator(
+    mut dispatcher: Authenticator,
+    requests: Receiver<'static, NoopRawMutex, CtapRequest, CTAP_REQUEST_LEN>,
+    sender: Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
 ) {
-    if let Ok(request) = ctap_types::ctap2::Request::deserialize(buf) {
-        let result = Rpc::call(ctap, &request);
-        match result {
-            Ok(result) => {
-                let mut buf = ctap_types::Vec::<_, 64>::new();
-                result.serialize(&mut buf);
-                sender.send(buf.into_array().unwrap()).await;
-            }
-            Err(err) => {
-                let mut buf = [0; 64];
-                buf[0] = err as u8;
-                sender.send(buf).await;
-            }
+    loop {
+        let (id, request) = requests.receive().await;
+        // The host may have given up on it while it was queued
+        if abandoned(id) {
+            REQUEST_DONE.signal(id);
+            continue;
+        }
+
+        // A cancel that came in while it was queued still counts
+        CANCEL_SIGNAL.reset();
+        if CANCELLED.lock(Cell::get) == Some(id) {
+            CANCEL_SIGNAL.signal(());
         }
-        return;
+        KEEPALIVE.lock(|k| k.set(Some(request.cid)));
+        LED_SIGNAL.signal(LedState::Processing);
+
+        let response = ctaphid::dispatch(&mut dispatcher, &request).await;
+
+        // Stop the keepalives before the response goes out
+        KEEPALIVE.lock(|k| k.set(None));
+        LED_SIGNAL.signal(LedState::Idle);
+        if !abandoned(id) {
+            sender.send(response).await;
+        }
+        REQUEST_DONE.signal(id);
     }
-    // TODO: Handle CTAP1 requests
-    // if let Ok(request) = ctap_types::ctap1::Request::deserialize(buf) {
-    //     // HANDLE CTAP1 requests here
-    //     return;
-    // }
-    warn!("CTAP Request could not be deserialized as CTAP1 or CTAP2");
+}
+
+fn abandoned(id: RequestId) -> bool {
+    ABANDONED.lock(Cell::get).is_some_and(|last| id.up_to(last))
+}
+
+async fn write_packet(
+    writer: &mut HidWriter<'static, Driver<'static, USB>, CTAP_WRITER_BUF>,
+    packet: Packet,
+) -> Result<(), EndpointError> {
+    let report = CtapReport {
+        data_in: packet,
+        data_out: [0; 64], // THIS IS NOT NEEDED?
+    };
+    // Send the report.
+    writer
+        .write_serialize(&report)
+        .await
+        .inspect_err(|e| warn!("Failed to send report: {:?}", e))
 }
 
 #[embassy_executor::task]
@@ -51,80 +116,142 @@ pub async fn ctap_writer(
     mut writer: HidWriter<'static, Driver<'static, USB>, CTAP_WRITER_BUF>,
     receiver: Receiver<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
 ) {
+    let mut ticker = Ticker::every(KEEPALIVE_INTERVAL);
+
     loop {
-        let rep = receiver.receive().await;
-        info!("Writing ctap response to host");
-        let report = CtapReport {
-            data_in: rep,
-            data_out: [0; 64], // THIS IS NOT NEEDED?
+        let message = match select(receiver.receive(), ticker.next()).await {
+            Either::First(message) => message,
+            Either::Second(()) => {
+                if let Some(cid) = KEEPALIVE.lock(Cell::get) {
+                    let status = match USER_PRESENCE_PENDING.load(Ordering::Relaxed) {
+                        true => KeepaliveStatus::UpNeeded,
+                        false => KeepaliveStatus::Processing,
+                    };
+                    write_packet(&mut writer, status.packet(cid)).await.ok();
+                }
+                continue;
+            }
         };
-        // Send the report.
-        if let Err(e) = writer.write_serialize(&report).await {
-            warn!("Failed to send report: {:?}", e)
+
+        info!("Writing ctap response to host");
+        // Every packet of a response is written before the next response is
+        // taken off the channel, so packets of different channels never interleave
+        for packet in message.packets() {
+            if write_packet(&mut writer, packet).await.is_err() {
+                break;
+            }
         }
     }
 }
 
-// #[embassy_executor::task]
-// pub async fn ctap_reader(
-//     mut reader: HidReader<'static, Driver<'static, USB>, CTAP_READER_BUF>,
-//     mut sender: Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
-// ) {
-//     let mut buf = [0; CTAP_READER_BUF];
-//     // This is only used when receivng Sync
-//     // (The message(s) was too big to fit in one packget)
-//     let mut multi_buf: Vec<u8> = Vec::new();
-
-//     let mut ctap = Ctap;
-
-//     loop {
-//         let resp = reader.read(&mut buf).await;
-//         match resp {
-//             Ok(_) => {
-//                 if multi_buf.is_empty() {
-//                     handle_response(&mut ctap, &mut sender, &buf).await;
-//                 } else {
-//                     handle_response(&mut ctap, &mut sender, &multi_buf).await;
-//                     multi_buf.drain(..);
-//                 }
-//             }
-//             Err(ReadError::BufferOverflow) => warn!("Usb got BufferOverflow (Buffer too small)"),
-//             Err(ReadError::Disabled) => warn!("Ctap usb reader got Disabled)"),
-//             Err(ReadError::Sync(range)) => {
-//                 multi_buf.extend_from_slice(&buf[range]);
-//             }
-//         };
-//     }
-// }
+async fn read_packet(
+    reader: &mut HidReader<'static, Driver<'static, USB>, PACKET_SIZE>,
+    packet: &mut Packet,
+    ctaphid: &mut CtapHid,
+) -> bool {
+    let result = reader.read(packet).await;
+    if CTAPHID_RESET.try_take().is_some() {
+        ctaphid.reset();
+    }
+
+    match result {
+        Ok(PACKET_SIZE) => return true,
+        Ok(len) => warn!("Ignoring short ctaphid packet of {} bytes", len),
+        Err(ReadError::BufferOverflow) => warn!("Usb got BufferOverflow (Buffer too small)"),
+        Err(ReadError::Disabled) => {
+            warn!("Ctap usb reader got Disabled");
+            ctaphid.reset();
+            reader.ready().await;
+        }
+        Err(ReadError::Sync(_)) => warn!("Ctap usb reader lost packet sync"),
+    };
+    false
+}
 
 #[embassy_executor::task]
 pub async fn ctap_reader(
-    reader: HidReader<'static, Driver<'static, USB>, CTAP_READER_BUF>,
-    ctap_send: Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
+    mut reader: HidReader<'static, Driver<'static, USB>, PACKET_SIZE>,
+    sender: Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
+    requests: Sender<'static, NoopRawMutex, CtapRequest, CTAP_REQUEST_LEN>,
 ) {
-    let mut handler = CtapRequestHandler(ctap_send);
-    reader.run(false, &mut handler).await;
-}
+    let mut packet = [0; PACKET_SIZE];
+    let mut ctaphid = CtapHid::new();
 
-struct CtapRequestHandler(Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>);
-
-impl RequestHandler for CtapRequestHandler {
-    fn get_report(&mut self, id: ReportId, _buf: &mut [u8]) -> Option<usize> {
-        info!("Get report for {:?}", id);
-        None
-    }
+    loop {
+        // Wake up in time to drop a message the host stopped sending
+        let deadline = ctaphid.deadline();
+        let timeout = async move {
+            match deadline {
+                Some(deadline) => Timer::at(deadline).await,
+                None => future::pending().await,
+            }
+        };
 
-    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
-        info!("Set report for {:?}: {=[u8]}", id, data);
-        OutResponse::Accepted
-    }
+        let read = read_packet(&mut reader, &mut packet, &mut ctaphid);
+        match select3(read, timeout, REQUEST_DONE.wait()).await {
+            Either3::First(true) => {}
+            Either3::First(false) => continue,
+            Either3::Second(()) => {
+                if let Some(Event::Response(response)) = ctaphid.timeout() {
+                    sender.send(response.to_message()).await;
+                }
+                continue;
+            }
+            Either3::Third(id) => {
+                ctaphid.finish(id);
+                continue;
+            }
+        }
 
-    fn set_idle_ms(&mut self, id: Option<ReportId>, dur: u32) {
-        info!("Set idle rate for {:?} to {:?}", id, dur);
-    }
+        let (id, message) = match ctaphid.handle_packet(&packet) {
+            Some(Event::Request(id, message)) => (id, message),
+            Some(Event::Response(response)) => {
+                sender.send(response.to_message()).await;
+                continue;
+            }
+            // If the request is still queued the signal stops the abandoned
+            // one before it, which has no one waiting on it anyway
+            Some(Event::Cancel(id)) => {
+                info!("Host cancelled the pending request");
+                CANCELLED.lock(|c| c.set(Some(id)));
+                CANCEL_SIGNAL.signal(());
+                continue;
+            }
+            Some(Event::Abort(id, response)) => {
+                info!("Host abandoned the pending request");
+                ABANDONED.lock(|a| a.set(Some(id)));
+                CANCEL_SIGNAL.signal(());
+                sender.send(response.to_message()).await;
+                continue;
+            }
+            None => continue,
+        };
 
-    fn get_idle_ms(&mut self, id: Option<ReportId>) -> Option<u32> {
-        info!("Get idle rate for {:?}", id);
-        None
+        let cid = message.cid;
+        if message.request().is_some() {
+            // The channel stays busy until the authenticator is done
+            let mut request = CtapMessage {
+                cid,
+                cmd: message.cmd,
+                data: Payload::new(),
+            };
+            unwrap!(request.data.extend_from_slice(&message.data));
+            requests.send((id, request)).await;
+            continue;
+        }
+        match Command::try_from(message.cmd) {
+            Ok(Command::Wink) => {
+                LED_SIGNAL.signal(LedState::Wink);
+                sender.send(CtapMessage::new(cid, Command::Wink)).await;
+            }
+            _ => {
+                warn!("Unhandled ctaphid command: {:#x}", message.cmd);
+                sender
+                    .send(CtapMessage::error(cid, ErrorCode::InvalidCmd))
+                    .await;
+            }
+        }
+        // Nothing else was queued, the channel is free again
+        ctaphid.answered();
     }
 }
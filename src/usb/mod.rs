@@ -4,20 +4,19 @@ use embassy_rp::usb::Driver;
 use embassy_rp::{bind_interrupts, usb::InterruptHandler};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::channel::{Receiver, Sender};
-use embassy_sync::mutex::Mutex;
 use embassy_usb::class::hid::{HidReaderWriter, State};
 use embassy_usb::{Builder, Config, Handler, UsbDevice};
 
-use core::cell::RefCell;
 use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::*;
 use static_cell::StaticCell;
 use usbd_hid::descriptor::{CtapReport, KeyboardReport, KeyboardUsage, SerializedDescriptor};
 
-use super::{Ctap, CtapMessage, Keys};
+use super::{CtapMessage, CtapRequest};
 pub mod ctap;
-pub use ctap::{ctap_reader, ctap_writer};
-pub use ctap::{CTAP_CHANNEL_LEN, CTAP_READER_BUF, CTAP_WRITER_BUF};
+pub use ctap::{ctap_authenticator, ctap_reader, ctap_writer, CTAPHID_RESET};
+pub use ctap::{CTAP_CHANNEL_LEN, CTAP_REQUEST_LEN, CTAP_WRITER_BUF};
+use pico_fido_core::ctaphid::PACKET_SIZE;
 pub mod hid;
 pub use hid::{hid_reader, hid_writer, HID_CHANNEL_LEN};
 
@@ -32,10 +31,10 @@ async fn run_usb(mut usb: UsbDevice<'static, Driver<'static, USB>>) {
 
 pub fn create_usb_tasks(
     usb: USB,
-    _keys: RefCell<Mutex<NoopRawMutex, Keys>>,
     keyboard_recv: Receiver<'static, NoopRawMutex, KeyboardUsage, HID_CHANNEL_LEN>,
     ctap_send: Sender<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
     ctap_recv: Receiver<'static, NoopRawMutex, CtapMessage, CTAP_CHANNEL_LEN>,
+    request_send: Sender<'static, NoopRawMutex, CtapRequest, CTAP_REQUEST_LEN>,
 ) -> (
     SpawnToken<impl Sized>,
     SpawnToken<impl Sized>,
@@ -106,7 +105,7 @@ pub fn create_usb_tasks(
             max_packet_size: 64,
         };
         static STATE: StaticCell<State> = StaticCell::new();
-        HidReaderWriter::<_, CTAP_READER_BUF, CTAP_WRITER_BUF>::new(
+        HidReaderWriter::<_, PACKET_SIZE, CTAP_WRITER_BUF>::new(
             &mut builder,
             STATE.init(State::new()),
             config,
@@ -125,7 +124,7 @@ pub fn create_usb_tasks(
         hid_writer(hid_sender, keyboard_recv),
         hid_reader(hid_receiver),
         ctap_writer(ctap_sender, ctap_recv),
-        ctap_reader(ctap_receiver, ctap_send),
+        ctap_reader(ctap_receiver, ctap_send, request_send),
     )
 }
 
@@ -153,6 +152,7 @@ impl Handler for UsbHandler {
 
     fn reset(&mut self) {
         self.configured.store(false, Ordering::Relaxed);
+        CTAPHID_RESET.signal(());
         info!("Bus reset, the Vbus current limit is 100mA");
     }
 